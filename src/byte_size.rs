@@ -12,15 +12,33 @@
 //! See the License for the specific language governing permissions and
 //! limitations under the License.
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::fmt;
 use std::str::FromStr;
 
+const KB: usize = 1000;
+const MB: usize = KB * 1000;
+const GB: usize = MB * 1000;
+const TB: usize = GB * 1000;
+const PB: usize = TB * 1000;
+
 const KIB: usize = 1024;
 const MIB: usize = KIB * 1024;
 const GIB: usize = MIB * 1024;
+const TIB: usize = GIB * 1024;
+const PIB: usize = TIB * 1024;
+
+/// Units from largest to smallest, `new` picks the largest one dividing size exactly.
+const UNITS: [(&str, usize); 10] = [
+    ("PiB", PIB), ("PB", PB), ("TiB", TIB), ("TB", TB), ("GiB", GIB),
+    ("GB", GB), ("MiB", MIB), ("MB", MB), ("KiB", KIB), ("KB", KB),
+];
 
-#[derive(Clone, Debug)]
+/// A size in bytes written like `512`, `10 MiB`, `1.5G` or `2TB`.
+///
+/// `KiB`, `MiB` ... and the single letters `K`, `M` ... are 1024-based, `KB`, `MB` ... are
+/// 1000-based, all of them are case insensitive.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ByteSize {
     pub bytes: usize,
     pub raw: String,
@@ -28,16 +46,10 @@ pub struct ByteSize {
 
 impl ByteSize {
     pub fn new(bytes: usize) -> Self {
-        let mut raw = String::new();
-        if bytes >= GIB {
-            raw.push_str(&format!("{}GiB", bytes / GIB));
-        } else if bytes >= MIB {
-            raw.push_str(&format!("{}MiB", bytes / MIB));
-        } else if bytes >= KIB {
-            raw.push_str(&format!("{}KiB", bytes / KIB));
-        } else {
-            raw.push_str(&format!("{}B", bytes));
-        }
+        let raw = match UNITS.iter().find(|(_, scale)| bytes > 0 && bytes.is_multiple_of(*scale)) {
+            Some((unit, scale)) => format!("{}{}", bytes / scale, unit),
+            None => format!("{}B", bytes),
+        };
         ByteSize { bytes, raw }
     }
 }
@@ -46,36 +58,61 @@ impl FromStr for ByteSize {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let bytes ;
-        let mut num = String::new();
-        let mut unit = String::new();
-
-        let mut i = 0;
-        while i < s.len() && s.chars().nth(i).unwrap().is_digit(10) {
-            num.push(s.chars().nth(i).unwrap());
-            i += 1;
-        }
-        bytes = num.parse::<usize>()?;
+        let raw = s.trim();
+        let split = raw.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(raw.len());
+        let (num, unit) = raw.split_at(split);
 
-        while i < s.len() {
-            unit.push(s.chars().nth(i).unwrap());
-            i += 1;
+        let scale = match unit.trim_start().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kib" => KIB,
+            "m" | "mib" => MIB,
+            "g" | "gib" => GIB,
+            "t" | "tib" => TIB,
+            "p" | "pib" => PIB,
+            "kb" => KB,
+            "mb" => MB,
+            "gb" => GB,
+            "tb" => TB,
+            "pb" => PB,
+            _ => return Err(anyhow!("Invalid unit in byte size {}", s)),
+        };
+
+        let (int, frac) = num.split_once('.').unwrap_or((num, ""));
+        if int.is_empty() || (num.contains('.') && frac.is_empty()) {
+            return Err(anyhow!("Invalid number in byte size {}", s));
         }
+        let overflow = || anyhow!("Byte size {} overflows", s);
+        let mut bytes = int.parse::<usize>().map_err(|_| overflow())?
+            .checked_mul(scale)
+            .ok_or_else(overflow)?;
 
-        match unit.to_ascii_lowercase().as_str() {
-            "" => Ok(ByteSize::new(bytes)),
-            "b" => Ok(ByteSize::new(bytes)),
-            "k" => Ok(ByteSize::new(bytes * KIB)),
-            "kb" => Ok(ByteSize::new(bytes * KIB)),
-            "kib" => Ok(ByteSize::new(bytes * KIB)),
-            "m" => Ok(ByteSize::new(bytes * MIB)),
-            "mb" => Ok(ByteSize::new(bytes * MIB)),
-            "mib" => Ok(ByteSize::new(bytes * MIB)),
-            "g" => Ok(ByteSize::new(bytes * GIB)),
-            "gb" => Ok(ByteSize::new(bytes * GIB)),
-            "gib" => Ok(ByteSize::new(bytes * GIB)),
-            _ => Err(anyhow::anyhow!("Invalid unit")),
+        // digits beyond 20 are below one byte even for PiB, rounded down to whole bytes
+        if !frac.is_empty() {
+            if !frac.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(anyhow!("Invalid number in byte size {}", s));
+            }
+            let frac = &frac[..frac.len().min(20)];
+            let digits = frac.parse::<u128>()?;
+            let part = usize::try_from(digits * scale as u128 / 10u128.pow(frac.len() as u32))
+                .map_err(|_| overflow())?;
+            bytes = bytes.checked_add(part).ok_or_else(overflow)?;
         }
+
+        Ok(ByteSize { bytes, raw: raw.to_string() })
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl serde::Serialize for ByteSize {
+    fn serialize<S>(&self, ser: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        ser.serialize_str(&self.raw)
     }
 }
 
@@ -85,13 +122,13 @@ impl<'de> serde::Deserialize<'de> for ByteSize {
     {
         use serde::de::{Error, Visitor};
 
-        struct RegexVisitor;
+        struct ByteSizeVisitor;
 
-        impl<'de> Visitor<'de> for RegexVisitor {
+        impl<'de> Visitor<'de> for ByteSizeVisitor {
             type Value = ByteSize;
 
             fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                f.write_str("a bytes size string")
+                f.write_str("a bytes size string or number")
             }
 
             fn visit_str<E: Error>(self, v: &str) -> Result<ByteSize, E> {
@@ -99,8 +136,72 @@ impl<'de> serde::Deserialize<'de> for ByteSize {
                     E::custom(err.to_string())
                 })
             }
+
+            fn visit_u64<E: Error>(self, v: u64) -> Result<ByteSize, E> {
+                usize::try_from(v).map(ByteSize::new).map_err(|_| E::custom(format!("byte size {} overflows", v)))
+            }
+
+            fn visit_i64<E: Error>(self, v: i64) -> Result<ByteSize, E> {
+                u64::try_from(v).map_err(|_| E::custom(format!("invalid byte size {}", v))).and_then(|v| self.visit_u64(v))
+            }
+        }
+
+        // plain number is a size in bytes
+        de.deserialize_any(ByteSizeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(s: &str) -> usize {
+        ByteSize::from_str(s).unwrap().bytes
+    }
+
+    #[test]
+    fn byte_size_parse_test() {
+        assert_eq!(bytes("512"), 512);
+        assert_eq!(bytes("512B"), 512);
+        assert_eq!(bytes("10k"), 10 * KIB);
+        assert_eq!(bytes("10KiB"), 10 * KIB);
+        assert_eq!(bytes("10kb"), 10_000);
+        assert_eq!(bytes("10 MiB"), 10 * MIB);
+        assert_eq!(bytes(" 10mb "), 10_000_000);
+        assert_eq!(bytes("1.5G"), GIB + GIB / 2);
+        assert_eq!(bytes("0.5KB"), 500);
+        assert_eq!(bytes("0.1KiB"), 102);
+        assert_eq!(bytes("2TB"), 2_000_000_000_000);
+        assert_eq!(bytes("1PiB"), PIB);
+
+        assert!(ByteSize::from_str("").is_err());
+        assert!(ByteSize::from_str("MiB").is_err());
+        assert!(ByteSize::from_str("1.G").is_err());
+        assert!(ByteSize::from_str(".5G").is_err());
+        assert!(ByteSize::from_str("1.2.3G").is_err());
+        assert!(ByteSize::from_str("10 XB").is_err());
+        assert!(ByteSize::from_str("-1").is_err());
+        let err = ByteSize::from_str("99999999999PiB").unwrap_err();
+        assert!(err.to_string().contains("overflows"));
+        assert!(ByteSize::from_str("99999999999999999999999").is_err());
+        assert_eq!(bytes("1.999999999999999999999999999999PiB"), 2 * PIB - 1);
+    }
+
+    #[test]
+    fn byte_size_display_test() {
+        assert_eq!(ByteSize::new(0).to_string(), "0B");
+        assert_eq!(ByteSize::new(1000).to_string(), "1KB");
+        assert_eq!(ByteSize::new(3 * GIB / 2).to_string(), "1536MiB");
+        assert_eq!(ByteSize::new(1023).to_string(), "1023B");
+        assert_eq!(ByteSize::from_str(" 1.5 G ").unwrap().to_string(), "1.5 G");
+
+        for n in [0, 1, 1000, 1024, 2048, 1_500_000, 3 * GIB / 2, 7 * TB, PIB + 1] {
+            let size = ByteSize::new(n);
+            assert_eq!(ByteSize::from_str(&size.to_string()).unwrap(), size);
         }
 
-        de.deserialize_str(RegexVisitor)
+        let size = ByteSize::from_str("10 MiB").unwrap();
+        let yaml = serde_yaml::to_string(&size).unwrap();
+        assert_eq!(serde_yaml::from_str::<ByteSize>(&yaml).unwrap(), size);
     }
 }
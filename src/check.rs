@@ -0,0 +1,509 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Component, Path, PathBuf};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+use crate::config::{self, Entry, Format};
+use crate::rotate::Rotate;
+
+/// A problem of job list at 1-based line and column.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub line: usize,
+    pub column: usize,
+    pub job_opt: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        if let Some(i) = self.job_opt {
+            write!(f, "job {}: ", i)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Where a job and each of its fields start.
+#[derive(Debug, Default)]
+struct JobLocation {
+    start: (usize, usize),
+    fields: HashMap<String, (usize, usize)>,
+}
+
+/// Where top level keys, fields of defaults and jobs start in a job list file.
+#[derive(Debug, Default)]
+pub(crate) struct Locations {
+    pub keys: HashMap<String, (usize, usize)>,
+    defaults: HashMap<String, (usize, usize)>,
+    jobs: Vec<JobLocation>,
+}
+
+/// A collection being parsed, mappings alternate between keys and values.
+struct Frame {
+    map: bool,
+    key_next: bool,
+    key_opt: Option<String>,
+}
+
+/// Collects locations from parser events, jobs are mappings in the top level sequence, or in
+/// the sequence of `jobs` in the top level mapping.
+#[derive(Default)]
+struct Locator {
+    stack: Vec<Frame>,
+    locations: Locations,
+}
+
+impl Locator {
+    /// Depth of the stack inside the jobs sequence.
+    fn jobs_depth(&self) -> Option<usize> {
+        match self.stack.as_slice() {
+            [root, ..] if !root.map => Some(1),
+            [root, list, ..] if root.key_opt.as_deref() == Some("jobs") && !list.map => Some(2),
+            _ => None,
+        }
+    }
+
+    /// A value node starts, which is a job if it is directly in the jobs sequence.
+    fn value(&mut self, pos: (usize, usize)) {
+        if self.jobs_depth() == Some(self.stack.len()) {
+            self.locations.jobs.push(JobLocation { start: pos, ..JobLocation::default() });
+        }
+    }
+
+    /// A value node ends, the next node of its mapping is a key.
+    fn value_done(&mut self) {
+        if let Some(top) = self.stack.last_mut() {
+            top.key_next = top.map;
+        }
+    }
+}
+
+impl MarkedEventReceiver for Locator {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        let pos = (mark.line(), mark.col() + 1);
+        match ev {
+            Event::SequenceStart(_) | Event::MappingStart(_) => {
+                self.value(pos);
+                let map = matches!(ev, Event::MappingStart(_));
+                self.stack.push(Frame { map, key_next: map, key_opt: None });
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                self.stack.pop();
+                self.value_done();
+            }
+            Event::Scalar(v, ..) if self.stack.last().is_some_and(|top| top.key_next) => {
+                let in_job = self.jobs_depth().is_some_and(|d| self.stack.len() == d + 1);
+                let locations = &mut self.locations;
+                match self.stack.as_slice() {
+                    [_] => {
+                        locations.keys.entry(v.clone()).or_insert(pos);
+                    }
+                    [root, _] if root.key_opt.as_deref() == Some("defaults") => {
+                        locations.defaults.entry(v.clone()).or_insert(pos);
+                    }
+                    _ => {}
+                }
+                if let (true, Some(job)) = (in_job, locations.jobs.last_mut()) {
+                    // block mapping is marked at its first colon, point at the first key instead
+                    if job.fields.is_empty() {
+                        job.start = pos;
+                    }
+                    job.fields.entry(v.clone()).or_insert(pos);
+                }
+                let top = self.stack.last_mut().unwrap();
+                top.key_opt = Some(v);
+                top.key_next = false;
+            }
+            Event::Scalar(..) | Event::Alias(_) => {
+                self.value(pos);
+                self.value_done();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Table of toml file the following keys belong to.
+enum Section {
+    Top,
+    Defaults,
+    Job,
+    Other,
+}
+
+/// Locations in toml file, every `[[jobs]]` header starts a job whose fields are the keys until
+/// the next header, and `[jobs.<field>]` or `[defaults.<field>]` headers are fields themselves.
+fn toml_locations(content: &str) -> Locations {
+    let mut locations = Locations::default();
+    let mut section = Section::Top;
+    for (n, line) in content.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let pos = (n + 1, line.len() - trimmed.len() + 1);
+        if trimmed.starts_with('[') {
+            let name = trimmed.trim_start_matches('[').split(']').next().unwrap_or_default();
+            let (table, field_opt) = match name.split_once('.') {
+                Some((table, field)) => (table.trim(), Some(field.trim().trim_matches('"'))),
+                None => (name.trim(), None),
+            };
+            locations.keys.entry(table.trim_matches('"').to_string()).or_insert(pos);
+            section = match (table, field_opt) {
+                ("jobs", None) => {
+                    locations.jobs.push(JobLocation { start: pos, ..JobLocation::default() });
+                    Section::Job
+                }
+                ("defaults", None) => Section::Defaults,
+                ("jobs", Some(field)) => {
+                    if let Some(job) = locations.jobs.last_mut() {
+                        job.fields.entry(field.to_string()).or_insert(pos);
+                    }
+                    Section::Other
+                }
+                ("defaults", Some(field)) => {
+                    locations.defaults.entry(field.to_string()).or_insert(pos);
+                    Section::Other
+                }
+                _ => Section::Other,
+            };
+        } else if let Some((key, _)) = trimmed.split_once('=') {
+            let key = key.trim().trim_matches('"').to_string();
+            match (&section, locations.jobs.last_mut()) {
+                (Section::Top, _) => locations.keys.entry(key).or_insert(pos),
+                (Section::Defaults, _) => locations.defaults.entry(key).or_insert(pos),
+                (Section::Job, Some(job)) => job.fields.entry(key).or_insert(pos),
+                _ => continue,
+            };
+        }
+    }
+    locations
+}
+
+/// Locations of keys in job list file, json is parsed as yaml which only fails on unusual
+/// whitespace.
+pub(crate) fn locations(content: &str, format: Format) -> Locations {
+    match format {
+        Format::Toml => toml_locations(content),
+        Format::Yaml | Format::Json => {
+            let mut locator = Locator::default();
+            let _ = Parser::new(content.chars()).load(&mut locator, false);
+            locator.locations
+        }
+    }
+}
+
+/// Problems found in a single file, with its valid jobs and includes for checks across files.
+#[derive(Default)]
+struct FileCheck {
+    problems: Vec<Problem>,
+    jobs: Vec<(usize, (usize, usize), Rotate)>,
+    includes: Vec<(usize, (usize, usize), PathBuf)>,
+}
+
+fn check_file(content: &str, format: Format) -> FileCheck {
+    let mut check = FileCheck::default();
+    let values = match config::parse(content, format) {
+        Ok(values) => values,
+        Err(e) => {
+            check.problems.push(Problem { line: e.line, column: e.column, job_opt: None, message: e.message });
+            return check;
+        }
+    };
+
+    let locations = locations(content, format);
+    // a field the job omits is inherited from defaults, and located there
+    let locate = |i: usize, field_opt: Option<&str>| -> ((usize, usize), bool) {
+        let job = match locations.jobs.get(i) {
+            Some(job) => job,
+            None => return ((1, 1), false),
+        };
+        match field_opt {
+            Some(f) if job.fields.contains_key(f) => (job.fields[f], false),
+            Some(f) if locations.defaults.contains_key(f) => (locations.defaults[f], true),
+            _ => (job.start, false),
+        }
+    };
+    // problems of defaults are shared by every job inheriting them, report them once
+    let mut report = |i: usize, field_opt: Option<&str>, message: String| {
+        let ((line, column), inherited) = locate(i, field_opt);
+        let problem = Problem { line, column, job_opt: (!inherited).then_some(i), message };
+        if !check.problems.contains(&problem) {
+            check.problems.push(problem);
+        }
+    };
+
+    let mut jobs = vec![];
+    let mut includes = vec![];
+    for (i, value) in values.into_iter().enumerate() {
+        match Entry::from_value(value) {
+            Ok(Entry::Job(ro)) => {
+                for (field, message) in ro.validate() {
+                    report(i, Some(field), message);
+                }
+                jobs.push((i, locate(i, Some("path")).0, *ro));
+            }
+            Ok(Entry::Include(include)) => includes.push((i, locate(i, Some("include")).0, include)),
+            Err(e) => report(i, e.field_opt.as_deref(), e.message),
+        }
+    }
+    check.jobs = jobs;
+    check.includes = includes;
+    check
+}
+
+/// Parse and validate job list file at path, every job list file in dir and files they
+/// include, return all problems found with their files.
+pub fn check(path_opt: Option<&Path>, dir_opt: Option<&Path>, format_opt: Option<Format>) -> Result<Vec<(PathBuf, Problem)>> {
+    let mut problems = vec![];
+    let mut jobs: Vec<(PathBuf, usize, (usize, usize), Rotate)> = vec![];
+
+    config::walk(path_opt, dir_opt, format_opt, |path, format| {
+        let content = read_to_string(path).map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+        let check = check_file(&content, format);
+        problems.extend(check.problems.into_iter().map(|p| (path.to_path_buf(), p)));
+        jobs.extend(check.jobs.into_iter().map(|(i, pos, ro)| (path.to_path_buf(), i, pos, ro)));
+
+        let mut files = vec![];
+        for (i, (line, column), include) in check.includes {
+            match config::include_files(path, &include) {
+                Ok(included) => files.extend(included),
+                Err(e) => problems.push((path.to_path_buf(), Problem { line, column, job_opt: Some(i), message: e.to_string() })),
+            }
+        }
+        Ok(files)
+    })?;
+
+    for (n, (file, j, (line, column), ro)) in jobs.iter().enumerate() {
+        for (other_file, i, _, other) in jobs[..n].iter() {
+            for p in ro.get_paths() {
+                if let Some(q) = other.get_paths().iter().find(|q| overlaps(p, q)) {
+                    let mut message = format!("path {} overlaps with path {} of job {}", p.display(), q.display(), i);
+                    if other_file != file {
+                        message.push_str(&format!(" in {}", other_file.display()));
+                    }
+                    problems.push((file.clone(), Problem { line: *line, column: *column, job_opt: Some(*j), message }));
+                }
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Whether two paths or glob patterns may rotate the same file, a directory path covers its
+/// whole tree.
+fn overlaps(a: &Path, b: &Path) -> bool {
+    let (a_prefix, b_prefix) = (literal_prefix(a), literal_prefix(b));
+    match (a_prefix == a, b_prefix == b) {
+        (true, true) => a.starts_with(b) || b.starts_with(a),
+        (true, false) => covers(a, b, &b_prefix),
+        (false, true) => covers(b, a, &a_prefix),
+        (false, false) => a == b,
+    }
+}
+
+/// Whether literal path covers pattern, either by matching it or as an ancestor of it.
+fn covers(literal: &Path, pattern: &Path, pattern_prefix: &Path) -> bool {
+    pattern_prefix.starts_with(literal)
+        || Pattern::new(&pattern.to_string_lossy()).is_ok_and(|p| p.matches_path(literal))
+}
+
+/// Leading components of path without glob metacharacters.
+fn literal_prefix(path: &Path) -> PathBuf {
+    path.components()
+        .take_while(|c| match c {
+            Component::Normal(s) => !s.to_string_lossy().contains(['*', '?', '[']),
+            _ => true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    fn messages(content: &str, format: Format) -> Vec<String> {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("jobs");
+        write(&path, content).unwrap();
+        check(Some(&path), None, Some(format)).unwrap().iter().map(|(_, p)| p.to_string()).collect()
+    }
+
+    #[test]
+    fn check_yaml_test() {
+        let content = r#"
+- path: /var/log/a.log
+  keep: 3
+  mode: MoveCreate
+- path: var/log/b.log
+  keep: 3
+  mode: MoveCreate
+  regex: "("
+- path: var/log/c.log
+  keep: 3
+  mode: MoveCreate
+  precmd: [nonexistent-command]
+- path: [/var/log/*.log]
+  keep: 3
+  mode: CopyTruncate
+  size: 10 XB
+- keep: 3
+  mode: MoveCreate
+"#;
+        let problems = messages(content, Format::Yaml);
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems[0].starts_with("8:3: job 1: regex parse error"), "{}", problems[0]);
+        assert_eq!(problems[1], "9:3: job 2: path var/log/c.log must be absolute");
+        assert_eq!(problems[2], "12:3: job 2: precmd: command nonexistent-command not found");
+        assert!(problems[3].starts_with("16:3: job 3: Invalid unit"), "{}", problems[3]);
+        assert!(problems[4].starts_with("17:3: job 4: missing field `path`"), "{}", problems[4]);
+
+        let problems = messages("- path: /a\n  keep: 3\n- path: [/b/c, /a/d]\n  keep: 3\n  mode: MoveCreate\n", Format::Yaml);
+        assert!(problems[0].starts_with("1:3: job 0: missing field `mode`"), "{}", problems[0]);
+
+        let problems = messages("- path: /a\n  keep: 3\n  mode: MoveCreate\n  sise: 10M\n", Format::Yaml);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("4:3: job 0: unknown field `sise`"), "{}", problems[0]);
+
+        let problems = messages("- path: /a\n  keep: [3\n", Format::Yaml);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("3:1: "), "{}", problems[0]);
+    }
+
+    #[test]
+    fn check_json_test() {
+        let content = r#"[
+  {"path": "/var/log/a", "keep": 3, "mode": "MoveCreate"},
+  {
+    "path": "/var/log/a/b.log",
+    "keep": 3,
+    "mode": "CopyTruncate"
+  }
+]"#;
+        assert_eq!(messages(content, Format::Json), vec![
+            "4:5: job 1: path /var/log/a/b.log overlaps with path /var/log/a of job 0",
+        ]);
+
+        let problems = messages("[{\"path\": \"/a\",}]", Format::Json);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("1:16: "), "{}", problems[0]);
+    }
+
+    #[test]
+    fn check_defaults_test() {
+        let content = r#"
+defaults:
+  keep: 3
+  mode: MoveCreate
+  postcmd: [nonexistent-command]
+jobs:
+  - path: /var/log/a.log
+    postcmd: [sh, -c, "true"]
+  - path: /var/log/b.log
+    precmd: [nonexistent-command]
+  - {path: var/log/c.log, keep: 3}
+"#;
+        assert_eq!(messages(content, Format::Yaml), vec![
+            "10:5: job 1: precmd: command nonexistent-command not found",
+            "5:3: postcmd: command nonexistent-command not found",
+            "11:6: job 2: path var/log/c.log must be absolute",
+        ]);
+
+        let content = "defaults:\n  mode: MoveCreate\n  size: 10 XB\njobs:\n  - {path: /a, keep: 3}\n  - {path: /b, keep: 3}\n";
+        let problems = messages(content, Format::Yaml);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("3:3: Invalid unit"), "{}", problems[0]);
+
+        assert_eq!(messages("jobs: []\ndefaults: [keep]\n", Format::Yaml), vec!["2:1: defaults must be a map"]);
+        assert_eq!(messages("{\"jobs\": [],\n \"job\": []}", Format::Json), vec!["2:2: unknown field job, expected defaults or jobs"]);
+        assert_eq!(messages("jobs = 3\n", Format::Toml), vec!["1:1: jobs must be a list"]);
+        assert_eq!(messages("3\n", Format::Yaml), vec!["1:1: job list must be a list of jobs, or a map with defaults and jobs"]);
+    }
+
+    #[test]
+    fn check_toml_test() {
+        let content = r#"
+[[jobs]]
+path = "var/log/a.log"
+keep = 3
+mode = "MoveCreate"
+
+[jobs.create]
+mode = "0640"
+
+[[jobs]]
+path = "/var/log/b.log"
+keep = 3
+"#;
+        assert_eq!(messages(content, Format::Toml), vec![
+            "3:1: job 0: path var/log/a.log must be absolute",
+            "10:1: job 1: missing field `mode`",
+        ]);
+
+        let content = r#"
+[defaults]
+precmd = ["nonexistent-command"]
+
+[defaults.create]
+mode = "0640"
+
+[[jobs]]
+path = "/var/log/a.log"
+keep = 3
+mode = "MoveCreate"
+
+[jobs.create]
+mode = "0600"
+"#;
+        assert_eq!(messages(content, Format::Toml), vec!["3:1: precmd: command nonexistent-command not found"]);
+        assert!(messages("[[jobs]\n", Format::Toml)[0].starts_with("1:"));
+    }
+
+    #[test]
+    fn check_include_test() {
+        let dir = tempdir().unwrap();
+        let main = dir.path().join("main.yaml");
+        let other = dir.path().join("other.json");
+        write(&main, "- path: /var/log\n  keep: 3\n  mode: MoveCreate\n- include: other.json\n- include: missing.yaml\n").unwrap();
+        write(&other, r#"[{"path": "/var/log/a.log", "keep": 3, "mode": "MoveCreate"}]"#).unwrap();
+
+        let problems: Vec<String> = check(Some(&main), None, None).unwrap().iter()
+            .map(|(file, p)| format!("{}:{}", file.file_name().unwrap().to_str().unwrap(), p))
+            .collect();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("main.yaml:5:3: job 2: include "), "{}", problems[0]);
+        assert_eq!(problems[1], format!("other.json:1:3: job 0: path /var/log/a.log overlaps with path /var/log of job 0 in {}", main.display()));
+    }
+
+    #[test]
+    fn overlaps_test() {
+        let overlaps = |a: &str, b: &str| overlaps(Path::new(a), Path::new(b));
+        assert!(overlaps("/var/log/a.log", "/var/log/a.log"));
+        assert!(overlaps("/var/log", "/var/log/a.log"));
+        assert!(overlaps("/var/log/*.log", "/var/log/a.log"));
+        assert!(overlaps("/var/log", "/var/log/app/*.log"));
+        assert!(overlaps("/var/log/*.log", "/var/log/*.log"));
+        assert!(!overlaps("/var/log/a.log", "/var/log/b.log"));
+        assert!(!overlaps("/var/log/*.log", "/var/log/a.txt"));
+        assert!(!overlaps("/var/log/app/*.log", "/var/log/web"));
+    }
+}
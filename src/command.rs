@@ -0,0 +1,324 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::{anyhow, Result};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{getgrouplist, setgid, setgroups, setuid, Pid, Uid, User};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io::{BufRead, BufReader, Read};
+use std::env;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+use tracing::{info, warn, Span};
+
+/// How long to wait for output of exited command, pipes may be held by its background children.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Program of a command, either a shell string or an argv list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Program {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+impl Program {
+    fn into_argv(self) -> Vec<String> {
+        match self {
+            Program::Shell(s) => vec!["/bin/sh".to_string(), "-c".to_string(), s],
+            Program::Argv(argv) => argv,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CmdDef {
+    Program(Program),
+    Full {
+        cmd: Program,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        clearenv: bool,
+        cwd: Option<PathBuf>,
+        user: Option<String>,
+    },
+}
+
+/// A pre/post command, written as a shell string, an argv list, or a map with `cmd` and
+/// optional `env`, `clearenv`, `cwd` and `user`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "CmdDef")]
+pub struct Cmd {
+    pub argv: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub clearenv: bool,
+    pub cwd_opt: Option<PathBuf>,
+    pub user_opt: Option<String>,
+}
+
+impl From<CmdDef> for Cmd {
+    fn from(def: CmdDef) -> Self {
+        match def {
+            CmdDef::Program(program) => program.into_argv().into(),
+            CmdDef::Full { cmd, env, clearenv, cwd, user } => Cmd {
+                argv: cmd.into_argv(),
+                env,
+                clearenv,
+                cwd_opt: cwd,
+                user_opt: user,
+            },
+        }
+    }
+}
+
+impl From<Vec<String>> for Cmd {
+    fn from(argv: Vec<String>) -> Self {
+        Cmd { argv, env: BTreeMap::new(), clearenv: false, cwd_opt: None, user_opt: None }
+    }
+}
+
+impl Cmd {
+    /// Check program and user of command exist without running it.
+    pub fn check(&self) -> Result<()> {
+        let program = self.argv.first().ok_or_else(|| anyhow!("empty command"))?;
+        let found = if program.contains('/') {
+            let cwd = self.cwd_opt.clone().unwrap_or_default();
+            is_executable(&cwd.join(program))
+        } else {
+            // PATH of command is searched, which may be overridden by its env
+            let paths = self.env.get("PATH").map(Into::into).or_else(|| env::var_os("PATH")).unwrap_or_default();
+            env::split_paths(&paths).any(|dir| is_executable(&dir.join(program)))
+        };
+        if !found {
+            return Err(anyhow!("command {} not found", program));
+        }
+
+        if let Some(name) = &self.user_opt {
+            User::from_name(name)?.ok_or_else(|| anyhow!("user {} not found", name))?;
+        }
+        Ok(())
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Run command with extra environment variables and log its output line by line in the
+/// current span.
+///
+/// Fails if command exits with non-zero status, or is still running after timeout, in
+/// which case its whole process group is killed.
+pub fn run(cmd: &Cmd, env: &BTreeMap<String, String>, timeout_opt: Option<Duration>) -> Result<()> {
+    let argv = &cmd.argv;
+    if argv.is_empty() {
+        return Err(anyhow!("empty command"));
+    }
+
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    if cmd.clearenv {
+        command.env_clear();
+    }
+    if let Some(name) = &cmd.user_opt {
+        let user = User::from_name(name)?.ok_or_else(|| anyhow!("user {} not found", name))?;
+        // groups are looked up before fork, the child only makes syscalls; an unprivileged
+        // caller can only run commands as itself and keeps its groups
+        let groups_opt = if Uid::effective().is_root() {
+            Some(getgrouplist(&CString::new(name.as_str())?, user.gid)?)
+        } else {
+            None
+        };
+        let (uid, gid) = (user.uid, user.gid);
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(groups) = &groups_opt {
+                    setgroups(groups)?;
+                }
+                setgid(gid)?;
+                setuid(uid)?;
+                Ok(())
+            });
+        }
+        // login environment of user, unless env of command sets it
+        command.env("HOME", &user.dir).env("USER", name).env("LOGNAME", name);
+    }
+    command.envs(&cmd.env).envs(env);
+    if let Some(cwd) = &cmd.cwd_opt {
+        command.current_dir(cwd);
+    }
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| anyhow!("failed to run {}: {}", argv[0], e))?;
+
+    let (tx, rx) = channel();
+    log_lines(child.stdout.take().unwrap(), false, tx.clone());
+    log_lines(child.stderr.take().unwrap(), true, tx);
+
+    let status = wait(&mut child, timeout_opt);
+    for _ in 0..2 {
+        if rx.recv_timeout(DRAIN_TIMEOUT).is_err() {
+            break;
+        }
+    }
+
+    match status? {
+        Some(status) if status.success() => Ok(()),
+        Some(status) => Err(anyhow!("{} failed with {}", argv[0], status)),
+        None => Err(anyhow!("{} timed out after {:?}, killed", argv[0], timeout_opt.unwrap())),
+    }
+}
+
+/// Wait for child to exit, return `None` if it is killed after timeout.
+fn wait(child: &mut Child, timeout_opt: Option<Duration>) -> Result<Option<ExitStatus>> {
+    let deadline_opt = timeout_opt.map(|t| Instant::now() + t);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if deadline_opt.is_some_and(|d| Instant::now() >= d) {
+            // child leads its own process group, kill scripts spawned by it too
+            killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL)?;
+            child.wait()?;
+            return Ok(None);
+        }
+        sleep(Duration::from_millis(10));
+    }
+}
+
+/// Log every line of output in a background thread, notify tx when output is closed.
+fn log_lines<R: Read + Send + 'static>(output: R, stderr: bool, tx: Sender<()>) {
+    let span = Span::current();
+    thread::spawn(move || {
+        let _enter = span.enter();
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(line) if stderr => warn!(stream = "stderr", "{}", line),
+                Ok(line) => info!(stream = "stdout", "{}", line),
+                Err(_) => break,
+            }
+        }
+        let _ = tx.send(());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(list: &[&str]) -> Cmd {
+        list.iter().map(|s| s.to_string()).collect::<Vec<_>>().into()
+    }
+
+    #[test]
+    fn run_status_test() {
+        let env = BTreeMap::from([("NAME".to_string(), "a".to_string())]);
+        assert!(run(&argv(&["sh", "-c", "test \"$NAME\" = a && echo ok"]), &env, None).is_ok());
+        assert!(run(&argv(&["sh", "-c", "echo failed >&2; exit 3"]), &env, None).is_err());
+        assert!(run(&argv(&["/nonexistent"]), &env, None).is_err());
+        assert!(run(&argv(&[]), &env, None).is_err());
+    }
+
+    #[test]
+    fn run_env_cwd_test() {
+        let cmd: Cmd = serde_yaml::from_str(r#"
+cmd: test "$A" = 1 && test -z "$HOME" && test "$(pwd)" = /
+env: {A: "1"}
+clearenv: true
+cwd: /
+"#).unwrap();
+        assert!(run(&cmd, &BTreeMap::new(), None).is_ok());
+
+        let cmd = Cmd { cwd_opt: Some(PathBuf::from("/nonexistent")), ..cmd };
+        assert!(run(&cmd, &BTreeMap::new(), None).is_err());
+
+        let cmd = Cmd { user_opt: Some("nonexistent-user".to_string()), cwd_opt: None, ..cmd };
+        assert!(run(&cmd, &BTreeMap::new(), None).is_err());
+    }
+
+    #[test]
+    fn run_user_test() {
+        if !Uid::effective().is_root() {
+            return;
+        }
+        let user = User::from_uid(Uid::from_raw(1)).unwrap().unwrap();
+        let groups = getgrouplist(&CString::new(user.name.as_str()).unwrap(), user.gid).unwrap();
+        let mut groups: Vec<u32> = groups.iter().map(|g| g.as_raw()).collect();
+        groups.sort();
+        groups.dedup();
+        let groups: Vec<String> = groups.iter().map(|g| g.to_string()).collect();
+        let script = format!(
+            r#"test "$(id -u)" = 1 && test "$(id -G | tr ' ' '\n' | sort -nu | xargs)" = "{}" && test "$HOME" = {} && test "$USER" = {} && test "$LOGNAME" = {}"#,
+            groups.join(" "), user.dir.display(), user.name, user.name,
+        );
+        let cmd = Cmd { user_opt: Some(user.name.clone()), ..argv(&["sh", "-c", &script]) };
+        assert!(run(&cmd, &BTreeMap::new(), None).is_ok());
+
+        let env = BTreeMap::from([("HOME".to_string(), "/tmp".to_string())]);
+        let cmd = Cmd { user_opt: Some(user.name.clone()), env, ..argv(&["sh", "-c", "test \"$HOME\" = /tmp"]) };
+        assert!(run(&cmd, &BTreeMap::new(), None).is_ok());
+    }
+
+    #[test]
+    fn cmd_deserialize_test() {
+        let cmd: Cmd = serde_yaml::from_str("kill -HUP $(cat /run/nginx.pid)").unwrap();
+        assert_eq!(cmd.argv, vec!["/bin/sh", "-c", "kill -HUP $(cat /run/nginx.pid)"]);
+
+        let cmd: Cmd = serde_yaml::from_str("[nginx, -s, reopen]").unwrap();
+        assert_eq!(cmd, argv(&["nginx", "-s", "reopen"]));
+
+        let cmd: Cmd = serde_yaml::from_str("{cmd: [nginx, -s, reopen], user: www-data, cwd: /tmp}").unwrap();
+        assert_eq!(cmd.argv, vec!["nginx", "-s", "reopen"]);
+        assert_eq!(cmd.user_opt.as_deref(), Some("www-data"));
+        assert_eq!(cmd.cwd_opt, Some(PathBuf::from("/tmp")));
+        assert!(cmd.env.is_empty());
+    }
+
+    #[test]
+    fn cmd_check_test() {
+        assert!(argv(&["sh", "-c", "true"]).check().is_ok());
+        assert!(argv(&["/bin/sh"]).check().is_ok());
+        assert!(argv(&["nonexistent-command"]).check().unwrap_err().to_string().contains("not found"));
+        assert!(argv(&["/nonexistent"]).check().is_err());
+        assert!(argv(&[]).check().is_err());
+
+        let cmd = Cmd { cwd_opt: Some(PathBuf::from("/bin")), ..argv(&["./sh"]) };
+        assert!(cmd.check().is_ok());
+        let cmd = Cmd { user_opt: Some("nonexistent-user".to_string()), ..argv(&["sh"]) };
+        assert!(cmd.check().is_err());
+    }
+
+    #[test]
+    fn run_timeout_test() {
+        let start = Instant::now();
+        let res = run(&argv(&["sh", "-c", "sleep 10; sleep 10"]), &BTreeMap::new(), Some(Duration::from_millis(200)));
+        assert!(res.unwrap_err().to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,194 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::meta::copy_metadata;
+use std::ffi::OsString;
+use std::fs::{read_dir, remove_file, File};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// File extensions of all supported compression formats.
+pub const EXTENSIONS: [&str; 3] = ["gz", "zst", "xz"];
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+            Compression::Xz => "xz",
+        }
+    }
+}
+
+pub fn is_compressed(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => EXTENSIONS.contains(&ext),
+        None => false,
+    }
+}
+
+/// Split file name into the name without compression extension and the extension.
+pub fn split_extension(name: &str) -> (&str, Option<&str>) {
+    for ext in EXTENSIONS.iter() {
+        if let Some(base) = name.strip_suffix(ext).and_then(|s| s.strip_suffix('.')) {
+            return (base, Some(ext));
+        }
+    }
+    (name, None)
+}
+
+/// Compress file in place, directory is compressed file by file recursively.
+pub fn compress(path: &Path, kind: Compression) -> Result<()> {
+    if path.is_dir() {
+        for res in read_dir(path)? {
+            compress(&res?.path(), kind)?;
+        }
+        return Ok(());
+    }
+
+    if !path.is_file() || is_compressed(path) {
+        return Ok(());
+    }
+
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(kind.extension());
+    let dst = PathBuf::from(name);
+    info!(src = path.to_str().unwrap(), dst = dst.to_str().unwrap(), "compress");
+
+    compress_to(path, &dst, kind)?;
+    remove_file(path)?;
+
+    Ok(())
+}
+
+/// Compress path into dst which must not exist, a partial dst is removed on failure so the
+/// next attempt starts over.
+fn compress_to(path: &Path, dst: &Path, kind: Compression) -> Result<()> {
+    let mut src_f = File::open(path)?;
+    // never readable by others before metadata of src is copied, nor overwrite an existing archive
+    let dst_f = File::options().write(true).create_new(true).mode(0o600).open(dst)?;
+    let res = encode(&mut src_f, dst_f, kind).and_then(|dst_f| {
+        dst_f.sync_all()?;
+        copy_metadata(path, dst)
+    });
+    if res.is_err() {
+        let _ = remove_file(dst);
+    }
+    res
+}
+
+fn encode(src_f: &mut File, dst_f: File, kind: Compression) -> Result<File> {
+    let dst_f = match kind {
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(dst_f, flate2::Compression::default());
+            io::copy(src_f, &mut encoder)?;
+            encoder.finish()?
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(dst_f, 0)?;
+            io::copy(src_f, &mut encoder)?;
+            encoder.finish()?
+        }
+        Compression::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(dst_f, 6);
+            io::copy(src_f, &mut encoder)?;
+            encoder.finish()?
+        }
+    };
+    Ok(dst_f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use tempfile::tempdir;
+
+    fn decompress(path: &Path, kind: Compression) -> Vec<u8> {
+        let file = File::open(path).unwrap();
+        let mut buf = vec![];
+        match kind {
+            Compression::Gzip => flate2::read::GzDecoder::new(file).read_to_end(&mut buf).unwrap(),
+            Compression::Zstd => zstd::Decoder::new(file).unwrap().read_to_end(&mut buf).unwrap(),
+            Compression::Xz => xz2::read::XzDecoder::new(file).read_to_end(&mut buf).unwrap(),
+        };
+        buf
+    }
+
+    #[test]
+    fn split_extension_test() {
+        assert_eq!(split_extension("log.1.gz"), ("log.1", Some("gz")));
+        assert_eq!(split_extension("log.1.zst"), ("log.1", Some("zst")));
+        assert_eq!(split_extension("log.1"), ("log.1", None));
+        assert_eq!(split_extension("gz"), ("gz", None));
+    }
+
+    #[test]
+    fn compress_keep_mode_test() {
+        use std::fs::{metadata, set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("log.1");
+        File::create(&path).unwrap().write_all(b"secret\n").unwrap();
+        set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+
+        compress(&path, Compression::Gzip).unwrap();
+        let dst = dir.path().join("log.1.gz");
+        assert_eq!(metadata(&dst).unwrap().permissions().mode() & 0o7777, 0o600);
+
+        // existing archive is never overwritten
+        File::create(&path).unwrap();
+        assert!(compress(&path, Compression::Gzip).is_err());
+        assert!(path.exists());
+        assert_eq!(decompress(&dst, Compression::Gzip), b"secret\n");
+    }
+
+    #[test]
+    fn compress_fail_test() {
+        let dir = tempdir().unwrap();
+        let dst = dir.path().join("mem.gz");
+        // reading at offset 0 of own memory fails as it is never mapped
+        assert!(compress_to(Path::new("/proc/self/mem"), &dst, Compression::Gzip).is_err());
+        assert!(!dst.exists());
+    }
+
+    #[test]
+    fn compress_test() {
+        let dir = tempdir().unwrap();
+        for kind in [Compression::Gzip, Compression::Zstd, Compression::Xz] {
+            let path = dir.path().join("log.1");
+            let data = b"hello world\n".repeat(1024);
+            File::create(&path).unwrap().write_all(&data).unwrap();
+
+            compress(&path, kind).unwrap();
+            let dst = dir.path().join(format!("log.1.{}", kind.extension()));
+            assert!(!path.exists());
+            assert_eq!(decompress(&dst, kind), data);
+        }
+    }
+}
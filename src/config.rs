@@ -0,0 +1,391 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::{anyhow, Result};
+use serde_yaml::{Mapping, Value};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{canonicalize, read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+use crate::check;
+use crate::rotate::Rotate;
+
+/// Format of job list file.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// Format of job list file by its extension, `None` if it is not a job list file.
+    pub fn detect(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Some(Format::Yaml),
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// Malformed job list file, at 1-based line and column.
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// Entry of job list that can't be deserialized, with the field it was found at.
+#[derive(Debug)]
+pub struct EntryError {
+    pub field_opt: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(field) = &self.field_opt {
+            write!(f, "{}: ", field)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for EntryError {}
+
+/// An entry of job list, either a job or `include: <path>` of other job list files.
+pub enum Entry {
+    Job(Box<Rotate>),
+    Include(PathBuf),
+}
+
+/// Parse job list into entries not yet deserialized.
+///
+/// Job list is either a bare list of entries, or a document with `jobs` list and optional
+/// `defaults` whose fields are inherited by jobs of the file omitting them. A toml file is
+/// always a document, with jobs as `[[jobs]]` tables.
+pub fn parse(content: &str, format: Format) -> Result<Vec<Value>, SyntaxError> {
+    let doc = match format {
+        Format::Yaml => serde_yaml::from_str::<Value>(content).map_err(|e| {
+            let (line, column) = e.location().map_or((1, 1), |l| (l.line(), l.column()));
+            SyntaxError { line, column, message: e.to_string() }
+        })?,
+        Format::Json => serde_json::from_str::<serde_json::Value>(content)
+            .map(|v| serde_yaml::to_value(v).unwrap())
+            .map_err(|e| SyntaxError { line: e.line(), column: e.column(), message: e.to_string() })?,
+        Format::Toml => toml::from_str::<toml::Value>(content)
+            .map(|v| serde_yaml::to_value(v).unwrap())
+            .map_err(|e| {
+                let (line, column) = e.line_col().map_or((1, 1), |(l, c)| (l + 1, c + 1));
+                SyntaxError { line, column, message: e.to_string() }
+            })?,
+    };
+    entries(doc).map_err(|(key_opt, message)| {
+        // the document itself is malformed at its start, otherwise at the offending key
+        let pos_opt = key_opt.and_then(|key| check::locations(content, format).keys.get(&key).copied());
+        let (line, column) = pos_opt.unwrap_or((1, 1));
+        SyntaxError { line, column, message }
+    })
+}
+
+/// Entries of job list document with defaults applied, or the top level key it fails at.
+fn entries(doc: Value) -> Result<Vec<Value>, (Option<String>, String)> {
+    let doc = match doc {
+        Value::Sequence(list) => return Ok(list),
+        // empty file has no jobs
+        Value::Null => return Ok(vec![]),
+        Value::Mapping(doc) => doc,
+        _ => return Err((None, "job list must be a list of jobs, or a map with defaults and jobs".to_string())),
+    };
+
+    let mut defaults = Mapping::new();
+    let mut jobs = vec![];
+    for (key, value) in doc {
+        let key_opt = key.as_str().map(str::to_string);
+        match (key.as_str(), value) {
+            (Some("defaults"), Value::Mapping(m)) => defaults = m,
+            (Some("defaults"), Value::Null) => {}
+            (Some("defaults"), _) => return Err((key_opt, "defaults must be a map".to_string())),
+            (Some("jobs"), Value::Sequence(list)) => jobs = list,
+            (Some("jobs"), Value::Null) => {}
+            (Some("jobs"), _) => return Err((key_opt, "jobs must be a list".to_string())),
+            (Some(other), _) => return Err((key_opt, format!("unknown field {}, expected defaults or jobs", other))),
+            (None, _) => return Err((None, "field name must be a string".to_string())),
+        }
+    }
+
+    for job in jobs.iter_mut() {
+        // include entries take nothing from defaults
+        if let Value::Mapping(m) = job {
+            if !m.contains_key(&Value::from("include")) {
+                for (key, value) in defaults.iter() {
+                    if !m.contains_key(key) {
+                        m.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+    }
+    Ok(jobs)
+}
+
+impl Entry {
+    pub fn from_value(value: Value) -> Result<Entry, EntryError> {
+        if let Some(include) = value.get("include") {
+            let include_error = |message: &str| EntryError { field_opt: Some("include".to_string()), message: message.to_string() };
+            if value.as_mapping().map_or(0, |m| m.len()) > 1 {
+                return Err(include_error("include entry can't have other fields"));
+            }
+            let path = include.as_str().ok_or_else(|| include_error("include must be a path"))?;
+            return Ok(Entry::Include(PathBuf::from(path)));
+        }
+        match serde_path_to_error::deserialize(value) {
+            Ok(ro) => Ok(Entry::Job(Box::new(ro))),
+            Err(e) => {
+                // only the top level field is located, nested paths point at it
+                let field_opt = match e.path().iter().next() {
+                    Some(serde_path_to_error::Segment::Map { key }) => Some(key.clone()),
+                    _ => None,
+                };
+                Err(EntryError { field_opt, message: e.inner().to_string() })
+            }
+        }
+    }
+}
+
+/// Job list files in directory sorted by name, files of other extensions are ignored.
+pub fn dir_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for res in read_dir(dir).map_err(|e| anyhow!("failed to read dir {}: {}", dir.display(), e))? {
+        let path = res?.path();
+        if path.is_file() && Format::detect(&path).is_some() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Files included by a path relative to the including file, which may be a glob pattern, or
+/// a directory including every job list file in it.
+pub fn include_files(from: &Path, include: &Path) -> Result<Vec<PathBuf>> {
+    let path = from.parent().unwrap_or(Path::new("")).join(include);
+    let pattern = path.to_str().ok_or_else(|| anyhow!("include is not valid unicode"))?;
+
+    if pattern.contains(['*', '?', '[']) {
+        let mut files = vec![];
+        for res in glob::glob(pattern)? {
+            let p = res?;
+            if p.is_dir() {
+                files.extend(dir_files(&p)?);
+            } else {
+                files.push(p);
+            }
+        }
+        return Ok(files);
+    }
+
+    if path.is_dir() {
+        dir_files(&path)
+    } else if path.exists() {
+        Ok(vec![path])
+    } else {
+        Err(anyhow!("include {} not found", path.display()))
+    }
+}
+
+/// Visit every job list file given by path, config dir and their includes once, visit returns
+/// the files included by the visited file. Format of file is detected by its extension, the
+/// format given is only used for path.
+pub fn walk<F>(path_opt: Option<&Path>, dir_opt: Option<&Path>, format_opt: Option<Format>, mut visit: F) -> Result<()>
+    where F: FnMut(&Path, Format) -> Result<Vec<PathBuf>>
+{
+    if path_opt.is_none() && dir_opt.is_none() {
+        return Err(anyhow!("either --path or --config-dir is needed"));
+    }
+
+    let mut files = vec![];
+    if let Some(path) = path_opt {
+        files.push((path.to_path_buf(), format_opt.or_else(|| Format::detect(path)).unwrap_or(Format::Yaml)));
+    }
+    if let Some(dir) = dir_opt {
+        files.extend(dir_files(dir)?.into_iter().map(|p| (p.clone(), Format::detect(&p).unwrap())));
+    }
+
+    let mut seen = HashSet::new();
+    for (path, format) in files {
+        visit_file(&path, format, &mut seen, &mut visit)?;
+    }
+    Ok(())
+}
+
+fn visit_file<F>(path: &Path, format: Format, seen: &mut HashSet<PathBuf>, visit: &mut F) -> Result<()>
+    where F: FnMut(&Path, Format) -> Result<Vec<PathBuf>>
+{
+    // a file included twice, or including itself, is loaded only once
+    if !seen.insert(canonicalize(path).unwrap_or_else(|_| path.to_path_buf())) {
+        return Ok(());
+    }
+    for include in visit(path, format)? {
+        let format = Format::detect(&include).unwrap_or(Format::Yaml);
+        visit_file(&include, format, seen, visit)?;
+    }
+    Ok(())
+}
+
+/// Load jobs of job list file at path, every job list file in dir, and files they include.
+pub fn load(path_opt: Option<&Path>, dir_opt: Option<&Path>, format_opt: Option<Format>) -> Result<Vec<Rotate>> {
+    let mut jobs = vec![];
+    walk(path_opt, dir_opt, format_opt, |path, format| {
+        let content = read_to_string(path).map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+        let values = parse(&content, format).map_err(|e| anyhow!("{}:{}", path.display(), e))?;
+
+        let mut includes = vec![];
+        for (i, value) in values.into_iter().enumerate() {
+            match Entry::from_value(value).map_err(|e| anyhow!("{}: job {}: {}", path.display(), i, e))? {
+                Entry::Job(ro) => jobs.push(*ro),
+                Entry::Include(include) => includes.extend(include_files(path, &include)?),
+            }
+        }
+        Ok(includes)
+    })?;
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, write};
+    use tempfile::tempdir;
+
+    fn job(path: &str) -> String {
+        format!("- path: {}\n  keep: 3\n  mode: MoveCreate\n", path)
+    }
+
+    fn paths(jobs: &[Rotate]) -> Vec<String> {
+        jobs.iter().map(|ro| ro.get_paths()[0].display().to_string()).collect()
+    }
+
+    #[test]
+    fn detect_test() {
+        assert_eq!(Format::detect(Path::new("/etc/filerotate.d/a.yml")), Some(Format::Yaml));
+        assert_eq!(Format::detect(Path::new("a.JSON")), Some(Format::Json));
+        assert_eq!(Format::detect(Path::new("a.toml")), Some(Format::Toml));
+        assert_eq!(Format::detect(Path::new("a.conf")), None);
+        assert_eq!(Format::detect(Path::new("yaml")), None);
+    }
+
+    #[test]
+    fn defaults_test() {
+        let content = r#"
+defaults:
+  keep: 5
+  mode: CopyTruncate
+  size: 10MiB
+jobs:
+  - path: /a
+  - path: /b
+    keep: 2
+    mode: MoveCreate
+  - include: conf.d
+"#;
+        let entries = parse(content, Format::Yaml).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].get("keep"), Some(&Value::from(5)));
+        assert_eq!(entries[0].get("size"), Some(&Value::from("10MiB")));
+        assert_eq!(entries[1].get("keep"), Some(&Value::from(2)));
+        assert_eq!(entries[1].get("mode"), Some(&Value::from("MoveCreate")));
+        assert_eq!(entries[1].get("size"), Some(&Value::from("10MiB")));
+        assert_eq!(entries[2].as_mapping().unwrap().len(), 1);
+
+        let content = r#"{"defaults": {"keep": 3, "mode": "MoveCreate"}, "jobs": [{"path": "/a"}]}"#;
+        assert!(matches!(Entry::from_value(parse(content, Format::Json).unwrap().remove(0)), Ok(Entry::Job(_))));
+
+        let content = "[defaults]\nkeep = 3\nmode = \"MoveCreate\"\n\n[[jobs]]\npath = \"/a\"\n";
+        assert!(matches!(Entry::from_value(parse(content, Format::Toml).unwrap().remove(0)), Ok(Entry::Job(_))));
+
+        // bare list has no defaults
+        assert_eq!(parse(&job("/a"), Format::Yaml).unwrap().len(), 1);
+        assert_eq!(parse("jobs:\n", Format::Yaml).unwrap().len(), 0);
+        assert!(parse("default: {keep: 3}\njobs: []\n", Format::Yaml).unwrap_err().message.contains("unknown field default"));
+        assert!(parse("defaults: [3]\njobs: []\n", Format::Yaml).is_err());
+        assert!(parse("3", Format::Yaml).is_err());
+    }
+
+    #[test]
+    fn integer_size_test() {
+        let dir = tempdir().unwrap();
+        let files = [
+            ("a.yaml", "- {path: /a, keep: 3, mode: MoveCreate, size: 1024, cmdtimeout: 30}\n"),
+            ("b.json", r#"[{"path": "/b", "keep": 3, "mode": "MoveCreate", "size": 1024}]"#),
+            ("c.toml", "[[jobs]]\npath = \"/c\"\nkeep = 3\nmode = \"MoveCreate\"\nsize = 1024\n"),
+        ];
+        for (name, content) in files {
+            write(dir.path().join(name), content).unwrap();
+        }
+        let jobs = load(None, Some(dir.path()), None).unwrap();
+        assert_eq!(jobs.len(), 3);
+        assert!(jobs.iter().all(|ro| format!("{:?}", ro).contains("sz_opt: Some(ByteSize { bytes: 1024")));
+        assert!(format!("{:?}", jobs[0]).contains("secs: 30"));
+
+        write(dir.path().join("a.yaml"), "- {path: /a, keep: 3, mode: MoveCreate, size: -1}\n").unwrap();
+        assert!(load(None, Some(dir.path()), None).is_err());
+    }
+
+    #[test]
+    fn load_dir_test() {
+        let dir = tempdir().unwrap();
+        let conf_d = dir.path().join("conf.d");
+        create_dir(&conf_d).unwrap();
+        write(conf_d.join("b.yaml"), job("/b")).unwrap();
+        write(conf_d.join("a.json"), r#"[{"path": "/a", "keep": 3, "mode": "MoveCreate"}]"#).unwrap();
+        write(conf_d.join("c.toml"), "[[jobs]]\npath = \"/c\"\nkeep = 3\nmode = \"CopyTruncate\"\n").unwrap();
+        write(conf_d.join("README"), "not a job list").unwrap();
+
+        let jobs = load(None, Some(&conf_d), None).unwrap();
+        assert_eq!(paths(&jobs), vec!["/a", "/b", "/c"]);
+
+        let main = dir.path().join("main.conf");
+        write(&main, format!("{}- include: conf.d\n- include: main.conf\n", job("/main"))).unwrap();
+        let jobs = load(Some(&main), None, None).unwrap();
+        assert_eq!(paths(&jobs), vec!["/main", "/a", "/b", "/c"]);
+
+        // file given by both path and dir is loaded once
+        let jobs = load(Some(&conf_d.join("b.yaml")), Some(&conf_d), None).unwrap();
+        assert_eq!(paths(&jobs), vec!["/b", "/a", "/c"]);
+
+        write(&main, "- include: conf.d/*.yaml\n").unwrap();
+        assert_eq!(paths(&load(Some(&main), None, None).unwrap()), vec!["/b"]);
+
+        write(&main, "- include: nonexistent.yaml\n").unwrap();
+        assert!(load(Some(&main), None, None).unwrap_err().to_string().contains("not found"));
+        write(&main, "- {include: conf.d, keep: 3}\n").unwrap();
+        assert!(load(Some(&main), None, None).is_err());
+        write(&main, "- path: /a\n  keep: [3\n").unwrap();
+        assert!(load(Some(&main), None, Some(Format::Yaml)).unwrap_err().to_string().contains("main.conf:"));
+        assert!(load(None, None, None).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use std::fmt;
+use std::str::FromStr;
+use anyhow::{Result};
+use chrono::{DateTime, Local};
+
+/// Cron expression, both 5 fields crontab form and 6 fields form with leading seconds are accepted.
+#[derive(Clone, Debug)]
+pub struct Cron(cron::Schedule);
+impl Cron {
+    pub fn new(expr: &str) -> Result<Self> {
+        let expr = expr.trim();
+        let schedule = if expr.split_whitespace().count() == 5 {
+            cron::Schedule::from_str(&format!("0 {}", expr))?
+        } else {
+            cron::Schedule::from_str(expr)?
+        };
+        Ok(Cron(schedule))
+    }
+
+    pub fn after(&self, t: &DateTime<Local>) -> Option<DateTime<Local>> {
+        self.0.after(t).next()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Cron {
+    fn deserialize<D>(de: D) -> Result<Cron, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        use serde::de::{Error, Visitor};
+
+        struct CronVisitor;
+
+        impl<'de> Visitor<'de> for CronVisitor {
+            type Value = Cron;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a cron expression")
+            }
+
+            fn visit_str<E: Error>(self, v: &str) -> Result<Cron, E> {
+                Cron::new(v).map_err(|err| {
+                    E::custom(err.to_string())
+                })
+            }
+        }
+
+        de.deserialize_str(CronVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn cron_after_test() {
+        let t = Local.with_ymd_and_hms(2022, 4, 18, 6, 30, 0).unwrap();
+        let cron = Cron::new("0 * * * *").unwrap();
+        assert_eq!(cron.after(&t), Some(Local.with_ymd_and_hms(2022, 4, 18, 7, 0, 0).unwrap()));
+        let cron = Cron::new("0 0 0 * * *").unwrap();
+        assert_eq!(cron.after(&t), Some(Local.with_ymd_and_hms(2022, 4, 19, 0, 0, 0).unwrap()));
+        assert!(Cron::new("every day").is_err());
+    }
+}
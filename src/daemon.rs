@@ -0,0 +1,108 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time;
+use tracing::{error, info};
+
+use crate::duration::Duration;
+use crate::rotate::Rotate;
+use crate::run;
+
+/// Next evaluation time of every job, `None` if the job will never run again.
+struct Schedule {
+    next: Vec<Option<DateTime<Local>>>,
+}
+
+impl Schedule {
+    /// Cron jobs wait for the next fire time, other jobs are evaluated immediately. Fails if
+    /// the schedule of any job can't be computed, before any job runs.
+    fn new(jobs: &[Rotate], now: DateTime<Local>, poll: &Duration) -> Result<Self> {
+        let mut next = vec![];
+        for (i, ro) in jobs.iter().enumerate() {
+            let run_opt = ro.next_run(now, poll).map_err(|e| anyhow!("job {}: {}", i, e))?;
+            next.push(if ro.has_cron() { run_opt } else { Some(now) });
+        }
+        Ok(Schedule { next })
+    }
+
+    fn due(&self, now: DateTime<Local>) -> Vec<usize> {
+        self.next.iter()
+            .enumerate()
+            .filter(|(_, t)| t.is_some_and(|t| t <= now))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn earliest(&self) -> Option<DateTime<Local>> {
+        self.next.iter().flatten().min().cloned()
+    }
+}
+
+/// Run jobs on their own schedule until SIGTERM or SIGINT, reload jobs on SIGHUP.
+pub fn run<F>(load: F, state_path: &Path, poll: &Duration) -> Result<()>
+    where F: Fn() -> Result<Vec<Rotate>> {
+    let reload = Arc::new(AtomicBool::new(false));
+    let term = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, reload.clone())?;
+    signal_hook::flag::register(SIGTERM, term.clone())?;
+    signal_hook::flag::register(SIGINT, term.clone())?;
+
+    let mut jobs = load()?;
+    let mut schedule = Schedule::new(&jobs, Local::now(), poll)?;
+    info!(jobs = jobs.len(), "daemon started");
+
+    while !term.load(Ordering::Relaxed) {
+        if reload.swap(false, Ordering::Relaxed) {
+            match load().and_then(|list| Ok((Schedule::new(&list, Local::now(), poll)?, list))) {
+                Ok((new_schedule, list)) => {
+                    jobs = list;
+                    schedule = new_schedule;
+                    info!(jobs = jobs.len(), "config reloaded");
+                }
+                Err(e) => error!(error = format!("{}", e).as_str(), "failed to reload config, keep old jobs"),
+            }
+        }
+
+        let now = Local::now();
+        let due = schedule.due(now);
+        if !due.is_empty() {
+            let list: Vec<&Rotate> = due.iter().map(|&i| &jobs[i]).collect();
+            if let Err(e) = run::rotate_jobs(&list, state_path, false) {
+                error!(error = format!("{}", e).as_str(), "failed to run jobs");
+            }
+            for i in due {
+                schedule.next[i] = jobs[i].next_run(now, poll).unwrap_or_else(|e| {
+                    error!(error = format!("{}", e).as_str(), "failed to schedule job, it won't run again");
+                    None
+                });
+            }
+        }
+
+        // wake up at least every second to handle signals
+        let wait = schedule.earliest()
+            .and_then(|t| (t - Local::now()).to_std().ok())
+            .map_or(time::Duration::from_secs(1), |d| d.min(time::Duration::from_secs(1)));
+        sleep(wait);
+    }
+
+    info!("daemon stopped");
+    Ok(())
+}
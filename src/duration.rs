@@ -0,0 +1,151 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = MINUTE * 60;
+const DAY: u64 = HOUR * 24;
+const WEEK: u64 = DAY * 7;
+/// Longest duration chrono can represent.
+const MAX_SECS: u64 = i64::MAX as u64 / 1000;
+
+/// A span of time written like `30s`, `12h`, `30d` or `1h30m`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Duration {
+    pub secs: u64,
+    pub raw: String,
+}
+
+impl Duration {
+    pub fn from_secs(secs: u64) -> Self {
+        Duration { secs, raw: format!("{}s", secs) }
+    }
+
+    pub fn to_std(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.secs)
+    }
+
+    /// Never fails as parsed durations are at most `MAX_SECS`.
+    pub fn to_chrono(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.secs as i64)
+    }
+
+    fn checked(self) -> Result<Self> {
+        if self.secs > MAX_SECS {
+            return Err(anyhow!("Duration out of range"));
+        }
+        Ok(self)
+    }
+}
+
+impl FromStr for Duration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut secs: u64 = 0;
+        let mut chars = s.trim().chars().peekable();
+
+        if chars.peek().is_none() {
+            return Err(anyhow!("Empty duration"));
+        }
+
+        while chars.peek().is_some() {
+            let mut num = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                num.push(c);
+            }
+            let n = num.parse::<u64>()?;
+
+            let mut unit = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+                unit.push(c);
+            }
+
+            let scale = match unit.to_ascii_lowercase().as_str() {
+                "" | "s" => 1,
+                "m" => MINUTE,
+                "h" => HOUR,
+                "d" => DAY,
+                "w" => WEEK,
+                _ => return Err(anyhow!("Invalid unit")),
+            };
+            secs = n.checked_mul(scale)
+                .and_then(|n| secs.checked_add(n))
+                .ok_or_else(|| anyhow!("Duration overflow"))?;
+        }
+
+        Duration { secs, raw: s.to_string() }.checked()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Duration {
+    fn deserialize<D>(de: D) -> Result<Duration, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        use serde::de::{Error, Visitor};
+
+        struct DurationVisitor;
+
+        impl<'de> Visitor<'de> for DurationVisitor {
+            type Value = Duration;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a duration string")
+            }
+
+            fn visit_str<E: Error>(self, v: &str) -> Result<Duration, E> {
+                Duration::from_str(v).map_err(|err| {
+                    E::custom(err.to_string())
+                })
+            }
+
+            fn visit_u64<E: Error>(self, v: u64) -> Result<Duration, E> {
+                Duration::from_secs(v).checked().map_err(|err| E::custom(err.to_string()))
+            }
+
+            fn visit_i64<E: Error>(self, v: i64) -> Result<Duration, E> {
+                let secs = u64::try_from(v).map_err(|_| E::custom(format!("invalid duration {}", v)))?;
+                self.visit_u64(secs)
+            }
+        }
+
+        // plain number is a duration in seconds
+        de.deserialize_any(DurationVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_parse_test() {
+        assert_eq!(Duration::from_str("30").unwrap().secs, 30);
+        assert_eq!(Duration::from_str("30s").unwrap().secs, 30);
+        assert_eq!(Duration::from_str("5m").unwrap().secs, 5 * MINUTE);
+        assert_eq!(Duration::from_str("12h").unwrap().secs, 12 * HOUR);
+        assert_eq!(Duration::from_str("30d").unwrap().secs, 30 * DAY);
+        assert_eq!(Duration::from_str("1w").unwrap().secs, WEEK);
+        assert_eq!(Duration::from_str("1h30m").unwrap().secs, HOUR + 30 * MINUTE);
+        assert!(Duration::from_str("").is_err());
+        assert!(Duration::from_str("d").is_err());
+        assert!(Duration::from_str("3y").is_err());
+        assert!(Duration::from_str("99999999999999999999w").is_err());
+        assert!(Duration::from_str("99999999999w").is_err());
+        assert_eq!(Duration::from_str("9999999999w").unwrap().to_chrono().num_weeks(), 9999999999);
+    }
+}
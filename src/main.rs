@@ -12,21 +12,54 @@
 //! See the License for the specific language governing permissions and
 //! limitations under the License.
 
-use std::fs::File;
-use tracing::{info, debug, error};
+use tracing::error;
 use clap::Parser;
-use std::io::BufReader;
+use std::io;
+use std::path::PathBuf;
+use std::process::exit;
+use serde::Serialize;
+use config::Format;
+use report::JobReport;
+use rotate::Rotate;
+use run::{paths_str, rotate_jobs};
 
 mod util;
 mod rotate;
 mod path_rule;
 mod regex;
 mod byte_size;
+mod compress;
+mod plan;
+mod state;
+mod duration;
+mod cron;
+mod daemon;
+mod watch;
+mod command;
+mod signal;
+mod meta;
+mod config;
+mod report;
+mod check;
+mod run;
 
 #[derive(clap::ArgEnum, Clone, Debug)]
-enum Format {
+enum PlanFormat {
+    Human,
     Json,
-    Yaml,
+}
+
+#[derive(clap::ArgEnum, Clone, Debug)]
+enum ReportFormat {
+    Human,
+    Json,
+}
+
+#[derive(Serialize)]
+struct JobPlan<'a> {
+    paths: &'a Vec<PathBuf>,
+    actions: &'a Vec<plan::Action>,
+    error: Option<String>,
 }
 
 /// A file rotate tool
@@ -35,31 +68,137 @@ enum Format {
 struct Args {
     /// path of job list file
     #[clap(short, long)]
-    path: String,
+    path: Option<PathBuf>,
+
+    /// directory of job list files, every yaml, json and toml file in it is loaded
+    #[clap(long)]
+    config_dir: Option<PathBuf>,
+
+    /// format of job list file given by path, detected by its extension if not set
+    #[clap(arg_enum, short, long)]
+    format: Option<Format>,
+
+    /// path of state file recording last rotation time, used by jobs with frequency
+    #[clap(short, long, default_value = "/var/lib/filerotate/status")]
+    state: PathBuf,
+
+    /// print rotation plan without touching the filesystem
+    #[clap(long)]
+    dry_run: bool,
 
-    /// format of job list file
-    #[clap(arg_enum, short, long, default_value = "yaml")]
-    format: Format,
+    /// format of printed rotation plan in dry run mode
+    #[clap(arg_enum, long, default_value = "human")]
+    plan_format: PlanFormat,
+
+    /// format of summary report printed after jobs are run
+    #[clap(arg_enum, long, default_value = "human")]
+    report: ReportFormat,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Stay resident and run every job on its own interval or cron expression, reload job list on SIGHUP
+    Daemon {
+        /// interval to evaluate jobs without interval or cron, e.g. size thresholds
+        #[clap(long, default_value = "60s")]
+        poll: duration::Duration,
+    },
+    /// Stay resident and rotate jobs with size threshold as soon as their paths grow beyond it, using inotify
+    Watch {
+        /// delay between the first write and size check, writes in between trigger a single rotation
+        #[clap(long, default_value = "1s")]
+        debounce: duration::Duration,
+    },
+    /// Validate job list without rotating, print every problem with its line and column
+    Check,
 }
 
 fn main() {
-    // install global collector configured based on RUST_LOG env var.
-    tracing_subscriber::fmt::init();
+    // install global collector configured based on RUST_LOG env var, keep stdout for plan output.
+    tracing_subscriber::fmt().with_writer(io::stderr).init();
 
     let args = Args::parse();
-    let file = File::open(args.path).expect("invalid config file path");
-    let reader = BufReader::new(file);
-    let list: Vec<rotate::Rotate> = match args.format {
-        Format::Yaml => serde_yaml::from_reader(reader).expect("yaml was not well-formatted"),
-        Format::Json => serde_json::from_reader(reader).expect("json was not well-formatted"),
-    };
-
-    for ro in list.iter() {
-        info!(path = ro.get_path().to_str().unwrap(), "start to rotate");
-        debug!(rotate = format!("{:?}", ro).as_str());
-        ro.rotate().map_or_else(
-            |e| error!(error = format!("{}", e).as_str(), "failed to rotate"),
-            |_| info!("rotate success"),
-        );
+    let load_jobs = || config::load(args.path.as_deref(), args.config_dir.as_deref(), args.format);
+
+    match &args.command {
+        Some(Command::Daemon { poll }) => {
+            // failing to start is a bad job list or schedule, failures of jobs are only logged
+            if let Err(e) = daemon::run(load_jobs, &args.state, poll) {
+                error!(error = format!("{}", e).as_str(), "daemon failed");
+                exit(report::EXIT_CONFIG);
+            }
+            return;
+        }
+        Some(Command::Watch { debounce }) => {
+            watch::run(load_jobs, &args.state, debounce).expect("watch failed");
+            return;
+        }
+        Some(Command::Check) => {
+            let problems = check::check(args.path.as_deref(), args.config_dir.as_deref(), args.format)
+                .unwrap_or_else(|e| {
+                    error!(error = format!("{}", e).as_str(), "failed to read job list");
+                    exit(report::EXIT_CONFIG);
+                });
+            for (file, problem) in problems.iter() {
+                println!("{}:{}", file.display(), problem);
+            }
+            if !problems.is_empty() {
+                exit(report::EXIT_CONFIG);
+            }
+            println!("ok");
+            return;
+        }
+        None => {}
+    }
+
+    let list = load_jobs().unwrap_or_else(|e| {
+        error!(error = format!("{}", e).as_str(), "failed to load job list");
+        exit(report::EXIT_CONFIG);
+    });
+    let list: Vec<&Rotate> = list.iter().collect();
+    let plans = rotate_jobs(&list, &args.state, args.dry_run).unwrap_or_else(|e| {
+        error!(error = format!("{}", e).as_str(), "failed to open state file");
+        exit(report::EXIT_CONFIG);
+    });
+
+    if args.dry_run {
+        match args.plan_format {
+            PlanFormat::Human => {
+                for (ro, (plan, report)) in list.iter().zip(plans.iter()) {
+                    println!("{}:", paths_str(ro));
+                    for (i, action) in plan.actions().iter().enumerate() {
+                        println!("  {}. {}", i + 1, action);
+                    }
+                    if let Some(err) = &report.error {
+                        println!("  error: {}", err);
+                    }
+                }
+            }
+            PlanFormat::Json => {
+                let jobs: Vec<JobPlan> = list.iter().zip(plans.iter()).map(|(ro, (plan, report))| JobPlan {
+                    paths: ro.get_paths(),
+                    actions: plan.actions(),
+                    error: report.error.clone(),
+                }).collect();
+                println!("{}", serde_json::to_string_pretty(&jobs).unwrap());
+            }
+        }
+    }
+
+    let reports: Vec<JobReport> = plans.into_iter().map(|(_, report)| report).collect();
+    // plan is the output of dry run, summary would only repeat it
+    if !args.dry_run {
+        match args.report {
+            ReportFormat::Human => {
+                for report in reports.iter() {
+                    println!("{}", report);
+                }
+            }
+            ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&reports).unwrap()),
+        }
     }
+    exit(report::exit_code(&reports));
 }
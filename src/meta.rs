@@ -0,0 +1,258 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::{anyhow, Result};
+use nix::errno::Errno;
+use nix::libc::timespec;
+use nix::sys::stat::{fchmod, fstat, stat, utimensat, Mode, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{chown, fchown, Gid, Group, Uid, User};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::{set_permissions, File, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use tracing::info;
+
+/// Mode and ownership of the file created by MoveCreate, unset ones are copied from the
+/// rotated file, e.g. `{mode: "0640", owner: www-data, group: adm}`.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Create {
+    #[serde(rename = "mode", default, deserialize_with = "deserialize_mode")]
+    mode_opt: Option<u32>,
+    #[serde(rename = "owner")]
+    owner_opt: Option<String>,
+    #[serde(rename = "group")]
+    group_opt: Option<String>,
+    /// copy extended attributes as well, including SELinux label and ACLs
+    #[serde(default)]
+    xattrs: bool,
+}
+
+impl Create {
+    /// Create an empty file at path with mode and ownership of like, overridden by configured ones.
+    pub fn create(&self, path: &Path, like: &Path) -> Result<()> {
+        let like_f = File::open(like)?;
+        let st = fstat(like_f.as_raw_fd())?;
+
+        let uid = match &self.owner_opt {
+            Some(owner) => lookup_user(owner)?,
+            None => Uid::from_raw(st.st_uid),
+        };
+        let gid = match &self.group_opt {
+            Some(group) => lookup_group(group)?,
+            None => Gid::from_raw(st.st_gid),
+        };
+        let mode = self.mode_opt.unwrap_or(st.st_mode & 0o7777);
+
+        // nobody else can open the file before its mode is set, and a file already recreated
+        // by the writer is never clobbered
+        let f = match File::options().write(true).create_new(true).mode(0o600).open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                info!(path = path.to_str().unwrap(), "already recreated by writer, keep it as is");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match fchown(f.as_raw_fd(), Some(uid), Some(gid)) {
+            // ownership copied from like is kept as is if not permitted, like copy_metadata
+            Err(Errno::EPERM) if self.owner_opt.is_none() && self.group_opt.is_none() => {}
+            res => res?,
+        }
+        // chown clears set-id bits, set mode afterwards
+        fchmod(f.as_raw_fd(), Mode::from_bits_truncate(mode))?;
+
+        if self.xattrs {
+            copy_xattrs(like, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy mode, ownership, access and modification time and extended attributes of src to dst.
+///
+/// Ownership is kept as is if not permitted to change, mode is still copied so a copy of
+/// restricted file is never more permissive than the original.
+pub fn copy_metadata(src: &Path, dst: &Path) -> Result<()> {
+    let st = stat(src)?;
+
+    match chown(dst, Some(Uid::from_raw(st.st_uid)), Some(Gid::from_raw(st.st_gid))) {
+        Ok(()) | Err(Errno::EPERM) => {}
+        Err(e) => return Err(e.into()),
+    }
+    set_permissions(dst, Permissions::from_mode(st.st_mode & 0o7777))?;
+    copy_xattrs(src, dst)?;
+
+    let atime = TimeSpec::from(timespec { tv_sec: st.st_atime, tv_nsec: st.st_atime_nsec });
+    let mtime = TimeSpec::from(timespec { tv_sec: st.st_mtime, tv_nsec: st.st_mtime_nsec });
+    utimensat(None, dst, &atime, &mtime, UtimensatFlags::FollowSymlink)?;
+    Ok(())
+}
+
+/// Copy all extended attributes of src to dst, those not permitted to set are skipped.
+pub fn copy_xattrs(src: &Path, dst: &Path) -> Result<()> {
+    let names = match xattr::list(src) {
+        Ok(names) => names,
+        // filesystem without xattr support has nothing to copy
+        Err(e) if e.raw_os_error() == Some(nix::libc::ENOTSUP) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for name in names {
+        if let Some(value) = xattr::get(src, &name)? {
+            if let Err(e) = xattr::set(dst, &name, &value) {
+                if e.raw_os_error() != Some(nix::libc::EPERM) {
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn lookup_user(owner: &str) -> Result<Uid> {
+    if let Ok(uid) = owner.parse::<u32>() {
+        return Ok(Uid::from_raw(uid));
+    }
+    User::from_name(owner)?.map(|u| u.uid).ok_or_else(|| anyhow!("user {} not found", owner))
+}
+
+fn lookup_group(group: &str) -> Result<Gid> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Group::from_name(group)?.map(|g| g.gid).ok_or_else(|| anyhow!("group {} not found", group))
+}
+
+/// Accept octal mode as string like `"0640"`, or as unquoted number like `0640` whose
+/// digits are read as octal.
+fn deserialize_mode<'de, D>(de: D) -> Result<Option<u32>, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    use serde::de::{Error, Visitor};
+
+    struct ModeVisitor;
+
+    impl<'de> Visitor<'de> for ModeVisitor {
+        type Value = u32;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an octal file mode")
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<u32, E> {
+            u32::from_str_radix(v.trim_start_matches("0o"), 8)
+                .ok()
+                .filter(|&m| m <= 0o7777)
+                .ok_or_else(|| E::custom(format!("invalid mode {}", v)))
+        }
+
+        fn visit_u64<E: Error>(self, v: u64) -> Result<u32, E> {
+            self.visit_str(&v.to_string())
+        }
+
+        fn visit_i64<E: Error>(self, v: i64) -> Result<u32, E> {
+            self.visit_str(&v.to_string())
+        }
+    }
+
+    de.deserialize_any(ModeVisitor).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{metadata, remove_file};
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn create_deserialize_test() {
+        let create: Create = serde_yaml::from_str("{mode: \"0640\", owner: www-data, group: adm}").unwrap();
+        assert_eq!(create.mode_opt, Some(0o640));
+        assert_eq!(create.owner_opt.as_deref(), Some("www-data"));
+        assert_eq!(create.group_opt.as_deref(), Some("adm"));
+        assert!(!create.xattrs);
+
+        let create: Create = serde_yaml::from_str("{mode: 0600, xattrs: true}").unwrap();
+        assert_eq!(create.mode_opt, Some(0o600));
+        assert!(create.xattrs);
+
+        assert!(serde_yaml::from_str::<Create>("{mode: \"0789\"}").is_err());
+        assert!(serde_yaml::from_str::<Create>("{mode: \"17777\"}").is_err());
+    }
+
+    #[test]
+    fn create_like_test() {
+        let dir = tempdir().unwrap();
+        let like = dir.path().join("like");
+        let path = dir.path().join("path");
+        File::create(&like).unwrap();
+        set_permissions(&like, Permissions::from_mode(0o640)).unwrap();
+        let _ = xattr::set(&like, "user.test", b"a");
+
+        Create::default().create(&path, &like).unwrap();
+        // file recreated by writer meanwhile is left untouched
+        Create { mode_opt: Some(0o604), ..Create::default() }.create(&path, &like).unwrap();
+        let meta = metadata(&path).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o640);
+        assert_eq!(meta.uid(), metadata(&like).unwrap().uid());
+        assert_eq!(xattr::get(&path, "user.test").unwrap(), None);
+
+        let create = Create { mode_opt: Some(0o604), xattrs: true, ..Create::default() };
+        remove_file(&path).unwrap();
+        create.create(&path, &like).unwrap();
+        assert_eq!(metadata(&path).unwrap().mode() & 0o7777, 0o604);
+        assert_eq!(xattr::get(&path, "user.test").unwrap(), xattr::get(&like, "user.test").unwrap());
+
+        if Uid::effective().is_root() {
+            let create = Create { owner_opt: Some("1".to_string()), group_opt: Some("2".to_string()), ..Create::default() };
+            remove_file(&path).unwrap();
+            create.create(&path, &like).unwrap();
+            let meta = metadata(&path).unwrap();
+            assert_eq!((meta.uid(), meta.gid()), (1, 2));
+            assert_eq!(meta.mode() & 0o7777, 0o640);
+        }
+
+        let create = Create { owner_opt: Some("nonexistent-user".to_string()), ..Create::default() };
+        remove_file(&path).unwrap();
+        assert!(create.create(&path, &like).is_err());
+    }
+
+    #[test]
+    fn copy_metadata_test() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        File::create(&src).unwrap();
+        File::create(&dst).unwrap();
+        set_permissions(&src, Permissions::from_mode(0o600)).unwrap();
+        set_permissions(&dst, Permissions::from_mode(0o666)).unwrap();
+        let _ = xattr::set(&src, "user.test", b"a");
+        let atime = TimeSpec::from(timespec { tv_sec: 1000, tv_nsec: 1 });
+        let mtime = TimeSpec::from(timespec { tv_sec: 2000, tv_nsec: 2 });
+        utimensat(None, &src, &atime, &mtime, UtimensatFlags::FollowSymlink).unwrap();
+
+        copy_metadata(&src, &dst).unwrap();
+        let meta = metadata(&dst).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o600);
+        assert_eq!((meta.uid(), meta.gid()), (metadata(&src).unwrap().uid(), metadata(&src).unwrap().gid()));
+        assert_eq!((meta.atime(), meta.atime_nsec()), (1000, 1));
+        assert_eq!((meta.mtime(), meta.mtime_nsec()), (2000, 2));
+        assert_eq!(xattr::get(&dst, "user.test").unwrap(), xattr::get(&src, "user.test").unwrap());
+    }
+}
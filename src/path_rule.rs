@@ -12,19 +12,27 @@
 //! See the License for the specific language governing permissions and
 //! limitations under the License.
 
-use std::collections::HashSet;
+use std::cmp::{min, Reverse};
 use std::path::{Path, PathBuf};
-use crate::rotate::MAX_KEEP_NUM;
+use chrono::{NaiveDate, NaiveDateTime};
+use crate::compress::split_extension;
 
 pub trait PathRule {
     fn delete_paths(&self) -> &Vec<PathBuf>;
     fn rename_paths(&self) -> &Vec<PathBuf>;
     fn init_path(&self) -> Option<PathBuf>;
     fn next_path(&self, path: &Path) -> Option<PathBuf>;
+    /// Kept generations newest first, named as they will be after this rotation.
+    fn generations(&self) -> Vec<PathBuf>;
+    /// All existing generations newest first, including the ones to delete.
+    fn existing_paths(&self) -> Vec<PathBuf>;
+    /// Move kept generations matching `expired` to delete paths, `expired` gets the
+    /// generation path and the time embedded in its name if any.
+    fn expire(&mut self, expired: &dyn Fn(&Path, Option<NaiveDateTime>) -> bool);
 }
 
 pub struct DefaultRule {
-    set: Vec<PathBuf>,
+    init: PathBuf,
     deletes: Vec<PathBuf>,
     renames: Vec<PathBuf>,
     init_opt: Option<PathBuf>,
@@ -32,65 +40,162 @@ pub struct DefaultRule {
 
 impl DefaultRule {
     pub fn new(init: PathBuf, paths: Vec<PathBuf>, keep: usize) -> DefaultRule {
-        assert!(keep <= MAX_KEEP_NUM && keep > 1);
+        assert!(keep > 1);
         let pos = keep - 1;
-        let mut set = vec![];
-        let mut delete_set = HashSet::new();
-        let mut rename_set = HashSet::new();
-        let init_name = init.file_name().unwrap().to_os_string();
-
-        set.push(init.clone());
-
-        for i in 1..pos {
-            let mut name = init_name.clone();
-            let mut path = init.clone();
-            name.push(".");
-            name.push(i.to_string());
-            path.set_file_name(name);
-            rename_set.insert(path.clone());
-            set.push(path);
-        }
-
-        for i in pos..MAX_KEEP_NUM {
-            let mut name = init_name.clone();
-            let mut path = init.clone();
-            name.push(".");
-            name.push(i.to_string());
-            path.set_file_name(name);
-            delete_set.insert(path.clone());
-            set.push(path)
-        }
-
         let mut deletes = vec![];
         let mut renames = vec![];
         let mut init_opt = None;
 
         for p in paths.iter() {
-            if delete_set.contains(p) {
-                deletes.push(p.into());
+            if init.eq(p) {
+                init_opt.replace(p.into());
+                continue;
             }
 
-            if rename_set.contains(p) {
-                renames.push(p.into());
+            match parse_index(&init, p) {
+                Some(i) if i >= pos => deletes.push((i, p.clone())),
+                Some(i) => renames.push((i, p.clone())),
+                None => {}
             }
+        }
+
+        deletes.sort();
+        renames.sort_by(|a, b| b.cmp(a));
+
+        DefaultRule {
+            init,
+            deletes: deletes.into_iter().map(|(_, p)| p).collect(),
+            renames: renames.into_iter().map(|(_, p)| p).collect(),
+            init_opt,
+        }
+    }
+}
+
+impl PathRule for DefaultRule {
+    fn delete_paths(&self) -> &Vec<PathBuf> {
+        &self.deletes
+    }
+
+    fn rename_paths(&self) -> &Vec<PathBuf> {
+        &self.renames
+    }
+
+    fn init_path(&self) -> Option<PathBuf> { self.init_opt.clone() }
+
+    fn next_path(&self, path: &Path) -> Option<PathBuf> {
+        let init_name = self.init.file_name()?.to_str()?;
+        if self.init == path {
+            return Some(self.init.with_file_name(format!("{}.1", init_name)));
+        }
+
+        let i = parse_index(&self.init, path)?;
+        let name = path.file_name()?.to_str()?;
+        let name = match split_extension(name) {
+            (_, Some(ext)) => format!("{}.{}.{}", init_name, i + 1, ext),
+            (_, None) => format!("{}.{}", init_name, i + 1),
+        };
+        Some(path.with_file_name(name))
+    }
+
+    fn generations(&self) -> Vec<PathBuf> {
+        let mut generations: Vec<PathBuf> = self.renames.iter().rev().filter_map(|p| self.next_path(p)).collect();
+        if let Some(p) = self.init_path() {
+            generations.insert(0, self.next_path(&p).unwrap());
+        }
+        generations
+    }
+
+    fn existing_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.renames.iter().chain(self.deletes.iter()).cloned().collect();
+        paths.sort_by_key(|p| parse_index(&self.init, p));
+        paths
+    }
+
+    fn expire(&mut self, expired: &dyn Fn(&Path, Option<NaiveDateTime>) -> bool) {
+        let (olds, renames) = self.renames.drain(..).partition(|p| expired(p, None));
+        self.renames = renames;
+        self.deletes.extend::<Vec<PathBuf>>(olds);
+    }
+}
+
+/// Parse numeric suffix of a sibling named `name.N` or `name.N.<compression extension>`.
+fn parse_index(init: &Path, path: &Path) -> Option<usize> {
+    if path.parent() != init.parent() {
+        return None;
+    }
+
+    let init_name = init.file_name()?.to_str()?;
+    let (name, _) = split_extension(path.file_name()?.to_str()?);
+    let suffix = name.strip_prefix(init_name)?.strip_prefix('.')?;
+    let i = suffix.parse::<usize>().ok()?;
+
+    // reject forms like `log.01` or `log.+1`, they are not generations
+    if i == 0 || suffix != i.to_string() {
+        return None;
+    }
+    Some(i)
+}
 
+/// Names rotated generations after the rotation time, e.g. `app.log-2022-04-18`.
+///
+/// The pattern is a strftime pattern for the rotated file name, `{name}` is replaced by the
+/// origin file name, `{stem}` and `{ext}` by the file stem and the extension (with leading dot).
+pub struct DateRule {
+    existing: Vec<PathBuf>,
+    keeps: Vec<(NaiveDateTime, PathBuf)>,
+    deletes: Vec<PathBuf>,
+    renames: Vec<PathBuf>,
+    init: PathBuf,
+    init_opt: Option<PathBuf>,
+    next: PathBuf,
+}
+
+impl DateRule {
+    pub fn new(init: PathBuf, paths: Vec<PathBuf>, keep: usize, pattern: &str, now: NaiveDateTime) -> DateRule {
+        assert!(keep > 1);
+        let pos = keep - 1;
+        let fmt = expand_pattern(&init, pattern);
+        let mut generations = vec![];
+        let mut init_opt = None;
+
+        for p in paths.iter() {
             if init.eq(p) {
                 init_opt.replace(p.into());
+                continue;
+            }
+
+            if p.parent() != init.parent() {
+                continue;
+            }
+
+            let name_opt = p.file_name().and_then(|n| n.to_str()).map(|n| split_extension(n).0);
+            if let Some(t) = name_opt.and_then(|n| parse_time(n, &fmt)) {
+                generations.push((t, p.clone()));
             }
         }
 
-        renames.sort_by(|a: &PathBuf, b: &PathBuf| b.cmp(a));
+        // newest first, the generation about to be created takes one more slot
+        generations.sort_by_key(|g| Reverse(g.0));
+        let existing = generations.iter().map(|(_, p)| p.clone()).collect();
+        let mut keeps = generations;
+        let deletes = keeps.split_off(min(pos - 1, keeps.len())).into_iter().map(|(_, p)| p).collect();
 
-        DefaultRule {
-            set,
+        let mut next = init.clone();
+        next.set_file_name(now.format(&fmt).to_string());
+
+        DateRule {
+            existing,
+            keeps,
             deletes,
-            renames,
+            renames: vec![],
+            init,
             init_opt,
+            next,
         }
     }
 }
 
-impl PathRule for DefaultRule {
+impl PathRule for DateRule {
     fn delete_paths(&self) -> &Vec<PathBuf> {
         &self.deletes
     }
@@ -102,14 +207,74 @@ impl PathRule for DefaultRule {
     fn init_path(&self) -> Option<PathBuf> { self.init_opt.clone() }
 
     fn next_path(&self, path: &Path) -> Option<PathBuf> {
-        let n = self.set.len();
-        for i in 0..n - 1 {
-            if self.set[i] == path {
-                return Some(self.set[i + 1].clone());
-            }
+        if self.init == path {
+            return Some(self.next.clone());
         }
         None
     }
+
+    fn generations(&self) -> Vec<PathBuf> {
+        let mut generations: Vec<PathBuf> = self.keeps.iter().map(|(_, p)| p.clone()).collect();
+        if self.init_opt.is_some() {
+            generations.insert(0, self.next.clone());
+        }
+        generations
+    }
+
+    fn existing_paths(&self) -> Vec<PathBuf> {
+        self.existing.clone()
+    }
+
+    fn expire(&mut self, expired: &dyn Fn(&Path, Option<NaiveDateTime>) -> bool) {
+        let (olds, keeps): (Vec<_>, Vec<_>) = self.keeps.drain(..).partition(|(t, p)| expired(p, Some(*t)));
+        self.keeps = keeps;
+        self.deletes.extend(olds.into_iter().map(|(_, p)| p));
+    }
+}
+
+/// Substitute name placeholders of pattern, escaping `%` in the substituted parts.
+fn expand_pattern(init: &Path, pattern: &str) -> String {
+    let escape = |s: Option<&std::ffi::OsStr>| s.and_then(|s| s.to_str()).unwrap_or("").replace('%', "%%");
+    let ext = init.extension().map_or(String::new(), |e| format!(".{}", escape(Some(e))));
+    pattern
+        .replace("{name}", &escape(init.file_name()))
+        .replace("{stem}", &escape(init.file_stem()))
+        .replace("{ext}", &ext)
+}
+
+fn parse_time(name: &str, fmt: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(name, fmt).ok().or_else(|| {
+        NaiveDate::parse_from_str(name, fmt)
+            .ok()
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+    })
+}
+
+/// Whether path is a generation of init, either numbered or named by date pattern.
+pub fn is_generation(init: &Path, path: &Path, date_opt: Option<&str>) -> bool {
+    match date_opt {
+        Some(pattern) => {
+            if init == path || path.parent() != init.parent() {
+                return false;
+            }
+            let fmt = expand_pattern(init, pattern);
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| parse_time(split_extension(n).0, &fmt))
+                .is_some()
+        }
+        None => parse_index(init, path).is_some(),
+    }
+}
+
+/// Live path a numbered generation like `app.log.2` or `app.log.2.gz` belongs to.
+pub fn numbered_root(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let (base, suffix) = split_extension(name).0.rsplit_once('.')?;
+    if base.is_empty() || suffix.is_empty() || !suffix.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(path.with_file_name(base))
 }
 
 #[cfg(test)]
@@ -141,7 +306,8 @@ mod tests {
         assert_eq!(rule.init_path(), Some(PathBuf::from("/var/lib/log")));
         assert_eq!(rule.next_path(&PathBuf::from("/var/lib/log")), Some(PathBuf::from("/var/lib/log.1")));
         assert_eq!(rule.next_path(&PathBuf::from("/var/lib/log.1")), Some(PathBuf::from("/var/lib/log.2")));
-        assert_eq!(rule.next_path(&PathBuf::from("/var/lib/log.9")), None);
+        assert_eq!(rule.next_path(&PathBuf::from("/var/lib/log.9")), Some(PathBuf::from("/var/lib/log.10")));
+        assert_eq!(rule.next_path(&PathBuf::from("/var/lib/log.x")), None);
     }
 
     #[test]
@@ -164,8 +330,180 @@ mod tests {
             PathBuf::from("/var/lib/log.6"),
         ]);
 
+        assert_eq!(rule.existing_paths(), vec![
+            PathBuf::from("/var/lib/log.1"),
+            PathBuf::from("/var/lib/log.2"),
+            PathBuf::from("/var/lib/log.5"),
+            PathBuf::from("/var/lib/log.6"),
+        ]);
+
         assert_eq!(rule.init_path(), None);
         assert_eq!(rule.next_path(&PathBuf::from("/var/lib/log.1")), Some(PathBuf::from("/var/lib/log.2")));
         assert_eq!(rule.next_path(&PathBuf::from("/var/lib/log.3")), Some(PathBuf::from("/var/lib/log.4")));
     }
+
+    #[test]
+    fn default_rule_large_keep_test() {
+        let init = PathBuf::from("/var/lib/log");
+        let mut paths = vec![
+            PathBuf::from("/var/lib/log"),
+            PathBuf::from("/var/lib/log.01"),
+            PathBuf::from("/var/lib/other.3"),
+            PathBuf::from("/var/lib/log.35"),
+        ];
+        for i in 1..30 {
+            paths.push(PathBuf::from(format!("/var/lib/log.{}", i)));
+        }
+        let rule = DefaultRule::new(init, paths, 30);
+        assert_eq!(rule.renames.len(), 28);
+        assert_eq!(rule.renames[0], PathBuf::from("/var/lib/log.28"));
+        assert_eq!(rule.renames[18], PathBuf::from("/var/lib/log.10"));
+        assert_eq!(rule.renames[19], PathBuf::from("/var/lib/log.9"));
+
+        assert_eq!(rule.deletes, vec![
+            PathBuf::from("/var/lib/log.29"),
+            PathBuf::from("/var/lib/log.35"),
+        ]);
+    }
+
+    #[test]
+    fn default_rule_compressed_test() {
+        let init = PathBuf::from("/var/lib/log");
+        let paths = vec![
+            PathBuf::from("/var/lib/log"),
+            PathBuf::from("/var/lib/log.1"),
+            PathBuf::from("/var/lib/log.2.gz"),
+            PathBuf::from("/var/lib/log.3.zst"),
+        ];
+        let rule = DefaultRule::new(init, paths, 4);
+        assert_eq!(rule.renames, vec![
+            PathBuf::from("/var/lib/log.2.gz"),
+            PathBuf::from("/var/lib/log.1"),
+        ]);
+
+        assert_eq!(rule.deletes, vec![
+            PathBuf::from("/var/lib/log.3.zst"),
+        ]);
+
+        assert_eq!(rule.next_path(&PathBuf::from("/var/lib/log.2.gz")), Some(PathBuf::from("/var/lib/log.3.gz")));
+        assert_eq!(rule.generations(), vec![
+            PathBuf::from("/var/lib/log.1"),
+            PathBuf::from("/var/lib/log.2"),
+            PathBuf::from("/var/lib/log.3.gz"),
+        ]);
+    }
+
+    #[test]
+    fn date_rule_simple_test() {
+        let init = PathBuf::from("/var/lib/app.log");
+        let paths = vec![
+            PathBuf::from("/var/lib/app.log"),
+            PathBuf::from("/var/lib/app.log-2022-04-15"),
+            PathBuf::from("/var/lib/app.log-2022-04-17"),
+            PathBuf::from("/var/lib/app.log-2022-04-16"),
+            PathBuf::from("/var/lib/app.log-2022-04-xx"),
+            PathBuf::from("/var/lib/app.log.1"),
+        ];
+        let now = NaiveDate::from_ymd_opt(2022, 4, 18).unwrap().and_hms_opt(6, 0, 0).unwrap();
+        let rule = DateRule::new(init, paths, 3, "{name}-%Y-%m-%d", now);
+        assert!(rule.rename_paths().is_empty());
+
+        assert_eq!(rule.deletes, vec![
+            PathBuf::from("/var/lib/app.log-2022-04-16"),
+            PathBuf::from("/var/lib/app.log-2022-04-15"),
+        ]);
+
+        assert_eq!(rule.init_path(), Some(PathBuf::from("/var/lib/app.log")));
+        assert_eq!(rule.next_path(&PathBuf::from("/var/lib/app.log")), Some(PathBuf::from("/var/lib/app.log-2022-04-18")));
+        assert_eq!(rule.next_path(&PathBuf::from("/var/lib/app.log-2022-04-17")), None);
+    }
+
+    #[test]
+    fn date_rule_time_ext_test() {
+        let init = PathBuf::from("/var/lib/app.log");
+        let paths = vec![
+            PathBuf::from("/var/lib/app-20220418T0500.log"),
+            PathBuf::from("/var/lib/app-20220418T0400.log"),
+            PathBuf::from("/var/lib/app-20220417T2300.log"),
+        ];
+        let now = NaiveDate::from_ymd_opt(2022, 4, 18).unwrap().and_hms_opt(6, 0, 0).unwrap();
+        let rule = DateRule::new(init, paths, 3, "{stem}-%Y%m%dT%H%M{ext}", now);
+
+        assert_eq!(rule.deletes, vec![
+            PathBuf::from("/var/lib/app-20220418T0400.log"),
+            PathBuf::from("/var/lib/app-20220417T2300.log"),
+        ]);
+
+        assert_eq!(rule.init_path(), None);
+        assert_eq!(rule.next_path(&PathBuf::from("/var/lib/app.log")), Some(PathBuf::from("/var/lib/app-20220418T0600.log")));
+    }
+
+    #[test]
+    fn default_rule_expire_test() {
+        let init = PathBuf::from("/var/lib/log");
+        let paths = vec![
+            PathBuf::from("/var/lib/log"),
+            PathBuf::from("/var/lib/log.1"),
+            PathBuf::from("/var/lib/log.2.gz"),
+            PathBuf::from("/var/lib/log.3"),
+            PathBuf::from("/var/lib/log.5"),
+        ];
+        let mut rule = DefaultRule::new(init, paths, 5);
+        rule.expire(&|p, t| {
+            assert_eq!(t, None);
+            p != Path::new("/var/lib/log.1")
+        });
+        assert_eq!(rule.renames, vec![
+            PathBuf::from("/var/lib/log.1"),
+        ]);
+
+        assert_eq!(rule.deletes, vec![
+            PathBuf::from("/var/lib/log.5"),
+            PathBuf::from("/var/lib/log.3"),
+            PathBuf::from("/var/lib/log.2.gz"),
+        ]);
+        assert_eq!(rule.generations(), vec![
+            PathBuf::from("/var/lib/log.1"),
+            PathBuf::from("/var/lib/log.2"),
+        ]);
+    }
+
+    #[test]
+    fn date_rule_expire_test() {
+        let init = PathBuf::from("/var/lib/app.log");
+        let paths = vec![
+            PathBuf::from("/var/lib/app.log-2022-04-15.gz"),
+            PathBuf::from("/var/lib/app.log-2022-04-17"),
+            PathBuf::from("/var/lib/app.log-2022-04-16"),
+        ];
+        let now = NaiveDate::from_ymd_opt(2022, 4, 18).unwrap().and_hms_opt(6, 0, 0).unwrap();
+        let limit = NaiveDate::from_ymd_opt(2022, 4, 16).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        let mut rule = DateRule::new(init, paths, 10, "{name}-%Y-%m-%d", now);
+        rule.expire(&|_, t| t.unwrap() < limit);
+
+        assert_eq!(rule.deletes, vec![
+            PathBuf::from("/var/lib/app.log-2022-04-16"),
+            PathBuf::from("/var/lib/app.log-2022-04-15.gz"),
+        ]);
+        assert_eq!(rule.generations(), vec![
+            PathBuf::from("/var/lib/app.log-2022-04-17"),
+        ]);
+    }
+
+    #[test]
+    fn is_generation_test() {
+        let init = PathBuf::from("/var/lib/app.log");
+        assert!(is_generation(&init, Path::new("/var/lib/app.log.1"), None));
+        assert!(is_generation(&init, Path::new("/var/lib/app.log.2.gz"), None));
+        assert!(!is_generation(&init, Path::new("/var/lib/app.log"), None));
+        assert!(!is_generation(&init, Path::new("/var/lib/other.log.1"), None));
+        assert!(is_generation(&init, Path::new("/var/lib/app.log-2022-04-17"), Some("{name}-%Y-%m-%d")));
+        assert!(!is_generation(&init, Path::new("/var/lib/app.log.1"), Some("{name}-%Y-%m-%d")));
+
+        assert_eq!(numbered_root(Path::new("/var/lib/app.log.1")), Some(PathBuf::from("/var/lib/app.log")));
+        assert_eq!(numbered_root(Path::new("/var/lib/app.log.2.zst")), Some(PathBuf::from("/var/lib/app.log")));
+        assert_eq!(numbered_root(Path::new("/var/lib/app.log.zst")), None);
+        assert_eq!(numbered_root(Path::new("/var/lib/app.log")), None);
+        assert_eq!(numbered_root(Path::new("/var/lib/.1")), None);
+    }
 }
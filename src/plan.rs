@@ -0,0 +1,294 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::Result;
+use nix::sys::signal::kill;
+use serde::Serialize;
+use tracing::warn;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{create_dir, metadata, remove_dir_all, remove_file, rename, File};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::command::{self, Cmd};
+use crate::compress::{self, Compression};
+use crate::meta::{self, Create};
+use crate::signal::SignalTarget;
+use crate::util::{self, CopyOptions};
+
+/// A single filesystem change or command made by rotation.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Delete { path: PathBuf },
+    Rename { src: PathBuf, dst: PathBuf },
+    Create { path: PathBuf },
+    CreateDir { path: PathBuf },
+    Copy { src: PathBuf, dst: PathBuf },
+    Truncate { path: PathBuf },
+    Command {
+        argv: Vec<String>,
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        env: BTreeMap<String, String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cwd: Option<PathBuf>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+    },
+    Compress { path: PathBuf, format: Compression },
+    Signal { signal: String, pid: i32 },
+    CopyMetadata { src: PathBuf, dst: PathBuf },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Delete { path } => write!(f, "delete {}", path.display()),
+            Action::Rename { src, dst } => write!(f, "rename {} -> {}", src.display(), dst.display()),
+            Action::Create { path } => write!(f, "create {}", path.display()),
+            Action::CreateDir { path } => write!(f, "create dir {}", path.display()),
+            Action::Copy { src, dst } => write!(f, "copy {} -> {}", src.display(), dst.display()),
+            Action::Truncate { path } => write!(f, "truncate {}", path.display()),
+            Action::Command { argv, env, cwd, user } => {
+                write!(f, "run ")?;
+                for (k, v) in env.iter() {
+                    write!(f, "{}={} ", k, v)?;
+                }
+                write!(f, "{}", argv.join(" "))?;
+                if let Some(user) = user {
+                    write!(f, " as {}", user)?;
+                }
+                if let Some(cwd) = cwd {
+                    write!(f, " in {}", cwd.display())?;
+                }
+                Ok(())
+            }
+            Action::Compress { path, format } => write!(f, "compress {} with {:?}", path.display(), format),
+            Action::Signal { signal, pid } => write!(f, "send {} to {}", signal, pid),
+            Action::CopyMetadata { src, dst } => write!(f, "copy metadata {} -> {}", src.display(), dst.display()),
+        }
+    }
+}
+
+/// Counters of a rotation, estimated from current file sizes in dry run mode.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// files moved or copied away from their live path
+    pub rotated: usize,
+    /// bytes of rotated files
+    pub bytes: u64,
+    /// files or directories deleted
+    pub deleted: usize,
+}
+
+/// Records every action of a rotation in order, and performs it unless in dry run mode.
+#[derive(Debug, Default)]
+pub struct Plan {
+    dry_run: bool,
+    actions: Vec<Action>,
+    stats: Stats,
+}
+
+impl Plan {
+    pub fn new(dry_run: bool) -> Self {
+        Plan { dry_run, actions: vec![], stats: Stats::default() }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn actions(&self) -> &Vec<Action> {
+        &self.actions
+    }
+
+    pub fn delete(&mut self, path: &Path) -> Result<()> {
+        self.actions.push(Action::Delete { path: path.into() });
+        if !self.dry_run {
+            if path.is_dir() {
+                remove_dir_all(path)?;
+            } else {
+                remove_file(path)?;
+            }
+        }
+        self.stats.deleted += 1;
+        Ok(())
+    }
+
+    pub fn rename(&mut self, src: &Path, dst: &Path) -> Result<()> {
+        self.actions.push(Action::Rename { src: src.into(), dst: dst.into() });
+        if !self.dry_run {
+            rename(src, dst)?;
+        }
+        Ok(())
+    }
+
+    pub fn create(&mut self, path: &Path) -> Result<()> {
+        self.actions.push(Action::Create { path: path.into() });
+        if !self.dry_run {
+            File::create(path)?;
+        }
+        self.stats.rotated += 1;
+        Ok(())
+    }
+
+    /// Create an empty file with mode and ownership of like, overridden by create.
+    pub fn create_like(&mut self, path: &Path, like: &Path, create: &Create) -> Result<()> {
+        self.actions.push(Action::Create { path: path.into() });
+        if !self.dry_run {
+            create.create(path, like)?;
+        }
+        self.stats.rotated += 1;
+        // data is still at path in dry run mode
+        self.stats.bytes += file_len(if self.dry_run { path } else { like });
+        Ok(())
+    }
+
+    pub fn create_dir(&mut self, path: &Path) -> Result<()> {
+        self.actions.push(Action::CreateDir { path: path.into() });
+        if !self.dry_run {
+            create_dir(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn copy_metadata(&mut self, src: &Path, dst: &Path) -> Result<()> {
+        self.actions.push(Action::CopyMetadata { src: src.into(), dst: dst.into() });
+        if !self.dry_run {
+            meta::copy_metadata(src, dst)?;
+        }
+        Ok(())
+    }
+
+    pub fn copy_truncate(&mut self, src: &Path, dst: &Path, opts: CopyOptions) -> Result<()> {
+        self.actions.push(Action::Copy { src: src.into(), dst: dst.into() });
+        self.actions.push(Action::Truncate { path: src.into() });
+        if !self.dry_run {
+            let lost = util::copy_truncate(src, dst, opts)?;
+            if lost > 0 {
+                warn!(path = src.to_str().unwrap(), lost_at_least = lost, "bytes written right before truncation are lost");
+            }
+        }
+        self.stats.rotated += 1;
+        self.stats.bytes += file_len(if self.dry_run { src } else { dst });
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, path: &Path) -> Result<()> {
+        self.actions.push(Action::Truncate { path: path.into() });
+        if !self.dry_run {
+            util::truncate(path)?;
+        }
+        Ok(())
+    }
+
+    /// Run command with extra environment variables on top of the inherited environment,
+    /// fails if command exits with non-zero status or is killed after timeout.
+    pub fn run(&mut self, cmd: &Cmd, env: BTreeMap<String, String>, timeout_opt: Option<Duration>) -> Result<()> {
+        let mut all_env = cmd.env.clone();
+        all_env.extend(env.clone());
+        self.actions.push(Action::Command {
+            argv: cmd.argv.clone(),
+            env: all_env,
+            cwd: cmd.cwd_opt.clone(),
+            user: cmd.user_opt.clone(),
+        });
+        if !self.dry_run {
+            command::run(cmd, &env, timeout_opt)?;
+        }
+        Ok(())
+    }
+
+    /// Send signal to target processes, they are looked up in dry run mode too.
+    pub fn signal(&mut self, target: &SignalTarget) -> Result<()> {
+        for pid in target.pids()? {
+            self.actions.push(Action::Signal { signal: target.name.as_str().to_string(), pid: pid.as_raw() });
+            if !self.dry_run {
+                kill(pid, target.name)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn compress(&mut self, path: &Path, format: Compression) -> Result<()> {
+        self.actions.push(Action::Compress { path: path.into(), format });
+        if !self.dry_run {
+            compress::compress(path, format)?;
+        }
+        Ok(())
+    }
+}
+
+/// Length of regular file at path, 0 for directories and missing paths.
+fn file_len(path: &Path) -> u64 {
+    metadata(path).ok().filter(|m| m.is_file()).map_or(0, |m| m.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn dry_run_test() {
+        let dir = tempdir().unwrap();
+        let path_a = dir.path().join("a");
+        let path_b = dir.path().join("b");
+        File::create(&path_a).unwrap();
+
+        let mut plan = Plan::new(true);
+        plan.rename(&path_a, &path_b).unwrap();
+        plan.create(&path_a).unwrap();
+        plan.delete(&path_b).unwrap();
+        assert!(path_a.exists());
+        assert!(!path_b.exists());
+
+        assert_eq!(plan.actions(), &vec![
+            Action::Rename { src: path_a.clone(), dst: path_b.clone() },
+            Action::Create { path: path_a.clone() },
+            Action::Delete { path: path_b.clone() },
+        ]);
+        assert_eq!(plan.stats(), Stats { rotated: 1, bytes: 0, deleted: 1 });
+        assert_eq!(plan.actions()[0].to_string(), format!("rename {} -> {}", path_a.display(), path_b.display()));
+        assert_eq!(
+            serde_json::to_string(&plan.actions()[2]).unwrap(),
+            format!(r#"{{"action":"delete","path":"{}"}}"#, path_b.display()),
+        );
+    }
+
+    #[test]
+    fn stats_test() {
+        let dir = tempdir().unwrap();
+        let path_a = dir.path().join("a");
+        let path_b = dir.path().join("b");
+        std::fs::write(&path_a, "12345").unwrap();
+        create_dir(&path_b).unwrap();
+
+        let mut plan = Plan::new(true);
+        plan.create_like(&path_a, &path_a, &Create::default()).unwrap();
+        plan.create_like(&path_b, &path_b, &Create::default()).unwrap();
+        assert_eq!(plan.stats(), Stats { rotated: 2, bytes: 5, deleted: 0 });
+
+        let mut plan = Plan::new(false);
+        assert!(plan.delete(&dir.path().join("missing")).is_err());
+        assert!(plan.create(&dir.path().join("missing").join("a")).is_err());
+        assert!(plan.copy_truncate(&dir.path().join("missing"), &path_b.join("a"), CopyOptions::default()).is_err());
+        assert_eq!(plan.stats(), Stats::default());
+    }
+}
@@ -0,0 +1,118 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::plan::{Plan, Stats};
+
+/// Exit code when every job succeeded.
+pub const EXIT_OK: i32 = 0;
+/// Exit code when every job failed.
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code when job list or state file can't be loaded, nothing is rotated.
+pub const EXIT_CONFIG: i32 = 2;
+/// Exit code when some jobs failed and others succeeded.
+pub const EXIT_PARTIAL: i32 = 3;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Failed,
+}
+
+/// Outcome of a single job in a run.
+#[derive(Serialize, Debug)]
+pub struct JobReport {
+    pub paths: Vec<PathBuf>,
+    pub status: Status,
+    #[serde(flatten)]
+    pub stats: Stats,
+    /// duration in seconds
+    pub duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl JobReport {
+    pub fn new(paths: &[PathBuf], plan: &Plan, err_opt: Option<&String>, elapsed: Duration) -> Self {
+        JobReport {
+            paths: paths.to_vec(),
+            status: if err_opt.is_some() { Status::Failed } else { Status::Ok },
+            stats: plan.stats(),
+            duration: elapsed.as_secs_f64(),
+            error: err_opt.cloned(),
+        }
+    }
+}
+
+impl fmt::Display for JobReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let paths = self.paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ");
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Failed => "failed",
+        };
+        write!(f, "{}: {}, rotated {} files ({} bytes), deleted {}, took {:.3}s",
+               paths, status, self.stats.rotated, self.stats.bytes, self.stats.deleted, self.duration)?;
+        if let Some(err) = &self.error {
+            write!(f, ", error: {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Exit code of a run by how many of its jobs failed.
+pub fn exit_code(reports: &[JobReport]) -> i32 {
+    let failed = reports.iter().filter(|r| r.status == Status::Failed).count();
+    if failed == 0 {
+        EXIT_OK
+    } else if failed == reports.len() {
+        EXIT_FAILURE
+    } else {
+        EXIT_PARTIAL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(err_opt: Option<&str>) -> JobReport {
+        let err_opt = err_opt.map(|e| e.to_string());
+        JobReport::new(&[PathBuf::from("/a")], &Plan::new(true), err_opt.as_ref(), Duration::from_millis(1500))
+    }
+
+    #[test]
+    fn exit_code_test() {
+        assert_eq!(exit_code(&[]), EXIT_OK);
+        assert_eq!(exit_code(&[report(None), report(None)]), EXIT_OK);
+        assert_eq!(exit_code(&[report(None), report(Some("e"))]), EXIT_PARTIAL);
+        assert_eq!(exit_code(&[report(Some("e")), report(Some("e"))]), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn report_format_test() {
+        let r = report(Some("boom"));
+        assert_eq!(r.to_string(), "/a: failed, rotated 0 files (0 bytes), deleted 0, took 1.500s, error: boom");
+        assert_eq!(
+            serde_json::to_string(&r).unwrap(),
+            r#"{"paths":["/a"],"status":"failed","rotated":0,"bytes":0,"deleted":0,"duration":1.5,"error":"boom"}"#,
+        );
+        assert!(!serde_json::to_string(&report(None)).unwrap().contains("error"));
+    }
+}
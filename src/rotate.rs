@@ -15,18 +15,24 @@
 use anyhow::{Result, anyhow};
 use tracing::{info};
 use nix::sys::stat::{FileStat, stat};
-use std::fs::{create_dir, read_dir, rename, remove_file, File, remove_dir_all};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{metadata, read_dir};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use serde::{Deserialize};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, Timelike};
 
-use crate::util;
 use crate::util::*;
 use crate::path_rule::*;
 use crate::regex::Regex;
 use crate::byte_size::ByteSize;
-
-pub const MAX_KEEP_NUM: usize = 10;
+use crate::compress::{self, Compression};
+use crate::plan::Plan;
+use crate::state::State;
+use crate::duration::Duration;
+use crate::cron::Cron;
+use crate::command::Cmd;
+use crate::signal::SignalTarget;
+use crate::meta::Create;
 
 #[derive(Deserialize, Debug)]
 pub enum Mode {
@@ -34,109 +40,512 @@ pub enum Mode {
     CopyTruncate,
 }
 
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum Frequency {
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl Frequency {
+    /// Rotation is due once `now` falls in a later period than `last`.
+    pub fn is_due(&self, last: NaiveDateTime, now: NaiveDateTime) -> bool {
+        self.period(now) > self.period(last)
+    }
+
+    fn period(&self, t: NaiveDateTime) -> (i32, u32, u32, u32) {
+        match self {
+            Frequency::Hourly => (t.year(), t.ordinal(), t.hour(), 0),
+            Frequency::Daily => (t.year(), t.ordinal(), 0, 0),
+            Frequency::Weekly => (t.iso_week().year(), t.iso_week().week(), 0, 0),
+            Frequency::Monthly => (t.year(), t.month(), 0, 0),
+            Frequency::Yearly => (t.year(), 0, 0, 0),
+        }
+    }
+}
+
+/// Paths moved by a job so far as `(src, dst)`, and rules whose compression and maxtotal
+/// wait for the shared signal and postcmd.
+#[derive(Default)]
+struct Batch {
+    rotated: Vec<(PathBuf, PathBuf)>,
+    deferred: Vec<(PathBuf, Box<dyn PathRule>)>,
+    precmd_done: bool,
+    aborted: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn one_or_many<'de, D, T>(de: D) -> Result<Vec<T>, D::Error>
+    where D: serde::Deserializer<'de>, T: Deserialize<'de>
+{
+    Ok(match OneOrMany::deserialize(de)? {
+        OneOrMany::One(v) => vec![v],
+        OneOrMany::Many(list) => list,
+    })
+}
+
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Rotate {
-    path: PathBuf,
+    #[serde(deserialize_with = "one_or_many")]
+    path: Vec<PathBuf>,
     keep: usize,
     #[serde(rename = "depth")]
     depth_opt: Option<i32>,
     #[serde(rename = "size")]
     sz_opt: Option<ByteSize>,
+    #[serde(rename = "minsize")]
+    minsize_opt: Option<ByteSize>,
+    #[serde(rename = "maxsize")]
+    maxsize_opt: Option<ByteSize>,
+    #[serde(rename = "sizekind", alias = "size_kind", default)]
+    size_kind: SizeKind,
     #[serde(rename = "regex")]
     re_opt: Option<Regex>,
     #[serde(rename = "precmd")]
-    pre_opt: Option<Vec<String>>,
+    pre_opt: Option<Cmd>,
     #[serde(rename = "postcmd")]
-    post_opt: Option<Vec<String>>,
+    post_opt: Option<Cmd>,
+    #[serde(default)]
+    sharedscripts: bool,
+    #[serde(rename = "signal")]
+    signal_opt: Option<SignalTarget>,
+    #[serde(default)]
+    create: Create,
+    #[serde(default)]
+    copylock: bool,
+    #[serde(default)]
+    copymethod: CopyMethod,
+    #[serde(rename = "cmdtimeout")]
+    cmd_timeout_opt: Option<Duration>,
+    #[serde(rename = "dateformat")]
+    date_opt: Option<String>,
+    #[serde(rename = "compress")]
+    compress_opt: Option<Compression>,
+    #[serde(default)]
+    delaycompress: bool,
+    #[serde(rename = "frequency")]
+    freq_opt: Option<Frequency>,
+    #[serde(rename = "maxage")]
+    maxage_opt: Option<Duration>,
+    #[serde(rename = "maxtotal")]
+    maxtotal_opt: Option<ByteSize>,
+    #[serde(rename = "interval")]
+    interval_opt: Option<Duration>,
+    #[serde(rename = "cron")]
+    cron_opt: Option<Cron>,
     mode: Mode,
 }
 
 impl Rotate {
-    pub fn rotate(&self) -> Result<()> {
-        let sz_opt = self.sz_opt.as_ref().map(|bz| bz.bytes);
+    /// Problems of the job found without touching the filesystem, as `(field, message)`.
+    pub fn validate(&self) -> Vec<(&'static str, String)> {
+        let mut problems = vec![];
+        if self.path.is_empty() {
+            problems.push(("path", "path is empty".to_string()));
+        }
+        for pattern in self.path.iter().filter(|p| !p.is_absolute()) {
+            problems.push(("path", format!("path {} must be absolute", pattern.display())));
+        }
+        if self.date_opt.is_some() && self.keep < 2 {
+            problems.push(("dateformat", format!("dateformat needs keep of at least 2, got {}", self.keep)));
+        }
+        if let Some(Err(e)) = self.pre_opt.as_ref().map(Cmd::check) {
+            problems.push(("precmd", format!("precmd: {}", e)));
+        }
+        if let Some(Err(e)) = self.post_opt.as_ref().map(Cmd::check) {
+            problems.push(("postcmd", format!("postcmd: {}", e)));
+        }
+        if let Some(Err(e)) = self.signal_opt.as_ref().map(SignalTarget::check) {
+            problems.push(("signal", e.to_string()));
+        }
+        if let Some(maxage) = self.maxage_opt.as_ref().filter(|d| Local::now().checked_sub_signed(d.to_chrono()).is_none()) {
+            problems.push(("maxage", format!("maxage {} is out of range", maxage.raw)));
+        }
+        problems
+    }
+
+    /// Rotate every path of the job, a failed path does not stop the others.
+    pub fn rotate(&self, plan: &mut Plan, state: &mut State) -> Result<()> {
+        let now = Local::now();
+        let mut errors = vec![];
+        let mut batch = Batch::default();
 
-        if !self.path.is_absolute() {
-            return Err(anyhow!("path must be absolute"));
+        for path in self.paths()? {
+            if let Err(e) = self.rotate_path(&path, plan, state, now, &mut batch) {
+                errors.push(format!("{}: {}", path.display(), e));
+            }
+            if batch.aborted {
+                break;
+            }
         }
 
+        // shared signal and postcmd run once after all paths, only if any path was rotated
+        if self.sharedscripts && !batch.rotated.is_empty() {
+            if let Err(e) = self.after_move(plan, script_env(&batch.rotated)) {
+                errors.push(e.to_string());
+            }
+        }
+
+        for (path, rule) in batch.deferred.iter() {
+            if let Err(e) = self.finish(path, plan, rule.as_ref(), now) {
+                errors.push(format!("{}: {}", path.display(), e));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!("{}", errors.join("; ")));
+        }
+        Ok(())
+    }
+
+    /// Expand globs in job paths, generations of matched paths are never matched as new roots.
+    pub fn paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        for pattern in self.path.iter() {
+            if !pattern.is_absolute() {
+                return Err(anyhow!("path must be absolute"));
+            }
+
+            let pattern_str = pattern.to_str().ok_or_else(|| anyhow!("path is not valid unicode"))?;
+            if !pattern_str.contains(['*', '?', '[']) {
+                paths.push(pattern.clone());
+                continue;
+            }
+
+            let mut matches = vec![];
+            for res in glob::glob(pattern_str)? {
+                matches.push(res?);
+            }
+            let date_opt = self.date_opt.as_deref();
+            let roots: Vec<PathBuf> = matches.iter()
+                .filter(|m| {
+                    let live_opt = numbered_root(m).filter(|r| r.exists());
+                    !matches.iter().chain(live_opt.iter()).any(|r| is_generation(r, m, date_opt))
+                })
+                .cloned()
+                .collect();
+            paths.extend(roots);
+        }
+
+        // overlapping patterns match the same path, rotate it only once
+        let mut seen = HashSet::new();
+        paths.retain(|p| seen.insert(p.clone()));
+        Ok(paths)
+    }
+
+    fn rotate_path(&self, path: &Path, plan: &mut Plan, state: &mut State, now: DateTime<Local>,
+                   batch: &mut Batch) -> Result<()> {
+        if !self.is_due(path, state, now) {
+            if !self.over_maxsize(path)? {
+                info!(path = path.to_str().unwrap(), "rotation not due, skipping");
+                return Ok(());
+            }
+            info!(path = path.to_str().unwrap(), "rotation not due, but bigger than maxsize");
+        }
+
+        let n = plan.actions().len();
+        let moved = batch.rotated.len();
+        let res = self.rotate_at(path, plan, now, batch);
+
+        // only record rotations which really happened, even if postcmd failed afterwards; deleting
+        // generations older than maxage while path itself is skipped is no rotation
+        let rotated = match self.keep {
+            0 | 1 => res.is_ok() && plan.actions().len() > n,
+            _ => batch.rotated.len() > moved,
+        };
+        if self.freq_opt.is_some() && rotated {
+            state.update(path, now);
+        }
+        res
+    }
+
+    pub fn has_frequency(&self) -> bool {
+        self.freq_opt.is_some()
+    }
+
+    pub fn has_cron(&self) -> bool {
+        self.cron_opt.is_some()
+    }
+
+    /// Next time daemon evaluates the job, jobs without cron or interval are polled. Fails if
+    /// interval or poll is too long to tell the time.
+    pub fn next_run(&self, after: DateTime<Local>, poll: &Duration) -> Result<Option<DateTime<Local>>> {
+        let (name, period) = match (&self.cron_opt, &self.interval_opt) {
+            (Some(cron), _) => return Ok(cron.after(&after)),
+            (None, Some(interval)) => ("interval", interval),
+            (None, None) => ("poll", poll),
+        };
+        let next = after.checked_add_signed(period.to_chrono())
+            .ok_or_else(|| anyhow!("{} {} is out of range", name, period.raw))?;
+        Ok(Some(next))
+    }
+
+    fn is_due(&self, path: &Path, state: &State, now: DateTime<Local>) -> bool {
+        match (self.freq_opt, state.last(path)) {
+            (Some(freq), Some(last)) => freq.is_due(last.naive_local(), now.naive_local()),
+            _ => true,
+        }
+    }
+
+    /// Both size and minsize have to be exceeded if both are set.
+    fn size_limit(&self) -> SizeLimit {
+        let min_opt = [&self.sz_opt, &self.minsize_opt].into_iter().flatten().map(|bz| bz.bytes).max();
+        SizeLimit { min_opt, kind: self.size_kind }
+    }
+
+    /// Like size and minsize, maxsize is compared with every file of path on its own.
+    fn over_maxsize(&self, path: &Path) -> Result<bool> {
+        match &self.maxsize_opt {
+            Some(maxsize) if path.exists() => Ok(max_file_size(path, self.size_kind, self.depth_opt)? > maxsize.bytes),
+            _ => Ok(false),
+        }
+    }
+
+    fn rotate_at(&self, path: &Path, plan: &mut Plan, now: DateTime<Local>, batch: &mut Batch) -> Result<()> {
+        let sz = self.size_limit();
+
         match self.keep {
-            0 => delete(self.path.clone(), self.depth_opt, sz_opt, self.re_opt.as_ref()),
-            1 => truncate(self.path.clone(), self.depth_opt, sz_opt, self.re_opt.as_ref()),
-            2..=MAX_KEEP_NUM => {
-                let f_st = stat(&self.path)?;
+            0 => delete(plan, path.into(), self.depth_opt, sz, self.re_opt.as_ref()),
+            1 => truncate(plan, path.into(), self.depth_opt, sz, self.re_opt.as_ref()),
+            _ => {
+                let f_st = stat(path)?;
 
                 if is_file(&f_st) {
                     // check if size hit threshold
-                    if !size_check(sz_opt, f_st) {
-                        info!(path = self.path.to_str().unwrap(), "size not matched, skipping");
-                        return Ok(());
+                    if !size_check(sz, f_st) {
+                        info!(path = path.to_str().unwrap(), "size not matched, skipping");
+                        return self.delete_expired(path, plan, now);
                     }
 
                     // check if name match regex
-                    if !regex_check(self.re_opt.as_ref(), &self.path) {
-                        info!(path = self.path.to_str().unwrap(), "regex not matched, skipping");
-                        return Ok(());
+                    if !regex_check(self.re_opt.as_ref(), path) {
+                        info!(path = path.to_str().unwrap(), "regex not matched, skipping");
+                        return self.delete_expired(path, plan, now);
                     }
                 }
 
-                let parent = self.path.parent().unwrap();
-                let entries = read_dir(parent)?;
-                let mut paths = vec![];
-                for res in entries {
-                    paths.push(res?.path());
+                let rule = self.path_rule(path, now)?;
+
+                if let Some(dst) = rule.init_path().and_then(|p| rule.next_path(&p)) {
+                    if dst.exists() && !rule.delete_paths().contains(&dst) && !rule.rename_paths().contains(&dst) {
+                        return Err(anyhow!("rotate target {} already exists", dst.display()));
+                    }
                 }
 
-                let rule = DefaultRule::new(self.path.clone(), paths, self.keep);
+                let dst_opt = rule.init_path().and_then(|p| rule.next_path(&p).map(|dst| (p, dst)));
+
+                // precmd runs before anything is touched, shared precmd only before the first path
+                if let (Some((p, dst)), Some(cmd)) = (&dst_opt, &self.pre_opt) {
+                    if !self.sharedscripts {
+                        plan.run(cmd, script_env(&[(p.clone(), dst.clone())]), self.cmd_timeout())
+                            .map_err(|e| anyhow!("precmd failed, not rotated: {}", e))?;
+                    } else if !batch.precmd_done {
+                        batch.precmd_done = true;
+                        if let Err(e) = plan.run(cmd, BTreeMap::new(), self.cmd_timeout()) {
+                            batch.aborted = true;
+                            return Err(anyhow!("shared precmd failed, job not rotated: {}", e));
+                        }
+                    }
+                }
 
                 for p in rule.delete_paths().iter() {
-                    if p.is_file() {
-                        remove_file(p)?;
-                    } else if p.is_dir() {
-                        remove_dir_all(p)?;
+                    if p.exists() {
+                        plan.delete(p)?;
                     }
                 }
 
                 for p in rule.rename_paths().iter() {
-                    rename(p, rule.next_path(p).unwrap())?;
+                    plan.rename(p, &rule.next_path(p).unwrap())?;
                 }
 
-                if let Some(p) = rule.init_path() {
-                    if let Some(cmd) = &self.pre_opt {
-                        Command::new(&cmd[0])
-                            .args(&cmd[1..])
-                            .output()?;
-                    }
+                if let Some((p, dst)) = dst_opt {
+                    let env = script_env(&[(p.clone(), dst.clone())]);
 
                     match self.mode {
-                        Mode::MoveCreate => move_create(p.clone(), rule.next_path(&p).unwrap(), self.depth_opt, sz_opt, self.re_opt.as_ref())?,
-                        Mode::CopyTruncate => copy_truncate(p.clone(), rule.next_path(&p).unwrap(), self.depth_opt, sz_opt, self.re_opt.as_ref())?,
+                        Mode::MoveCreate => move_create(plan, p.clone(), dst.clone(), &self.create, self.depth_opt, sz, self.re_opt.as_ref())?,
+                        Mode::CopyTruncate => copy_truncate(plan, p.clone(), dst.clone(), self.copy_options(), self.depth_opt, sz, self.re_opt.as_ref())?,
                     }
+                    batch.rotated.push((p.clone(), dst));
 
-                    if let Some(cmd) = &self.post_opt {
-                        Command::new(&cmd[0])
-                            .args(&cmd[1..])
-                            .output()?;
+                    if self.post_opt.is_some() || self.signal_opt.is_some() {
+                        if self.sharedscripts {
+                            // generations may still be written until shared signal and postcmd have run
+                            batch.deferred.push((path.into(), rule));
+                            return Ok(());
+                        }
+                        // path is rotated already, finish it before reporting the failure
+                        let res = self.after_move(plan, env);
+                        self.finish(path, plan, rule.as_ref(), now)?;
+                        return res;
                     }
                 }
 
-                Ok(())
+                self.finish(path, plan, rule.as_ref(), now)
+            }
+        }
+    }
+
+    /// Send signal to the writing process and run postcmd after paths are moved.
+    fn after_move(&self, plan: &mut Plan, env: BTreeMap<String, String>) -> Result<()> {
+        if let Some(target) = &self.signal_opt {
+            plan.signal(target).map_err(|e| anyhow!("signal failed: {}", e))?;
+        }
+        if let Some(cmd) = &self.post_opt {
+            plan.run(cmd, env, self.cmd_timeout()).map_err(|e| anyhow!("postcmd failed: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Compress generations and apply maxtotal after path is moved and postcmd has run.
+    fn finish(&self, path: &Path, plan: &mut Plan, rule: &dyn PathRule, now: DateTime<Local>) -> Result<()> {
+        if let (Some(kind), Some(_)) = (self.compress_opt, rule.init_path()) {
+            let skip = if self.delaycompress { 1 } else { 0 };
+            for p in rule.generations().iter().skip(skip) {
+                if !compress::is_compressed(p) {
+                    plan.compress(p, kind)?;
+                }
             }
-            _ => Err(anyhow!("keep must be less than {}", MAX_KEEP_NUM)),
         }
+
+        if let Some(maxtotal) = &self.maxtotal_opt {
+            self.limit_total(path, plan, rule, now, maxtotal.bytes)?;
+        }
+        Ok(())
+    }
+
+    fn path_rule(&self, path: &Path, now: DateTime<Local>) -> Result<Box<dyn PathRule>> {
+        let mut rule = self.keep_rule(path, now)?;
+        if let Some(maxage) = &self.maxage_opt {
+            let limit = now.naive_local().checked_sub_signed(maxage.to_chrono())
+                .ok_or_else(|| anyhow!("maxage {} is out of range", maxage.raw))?;
+            rule.expire(&|p, t_opt| t_opt.or_else(|| mtime(p)).is_some_and(|t| t < limit));
+        }
+        Ok(rule)
     }
 
-    pub fn get_path(&self) -> &PathBuf {
+    /// Rule of generations by keep only, without maxage.
+    fn keep_rule(&self, path: &Path, now: DateTime<Local>) -> Result<Box<dyn PathRule>> {
+        let parent = path.parent().unwrap();
+        let entries = read_dir(parent)?;
+        let mut paths = vec![];
+        for res in entries {
+            paths.push(res?.path());
+        }
+
+        Ok(match &self.date_opt {
+            Some(pattern) => Box::new(DateRule::new(path.into(), paths, self.keep, pattern, now.naive_local())),
+            None => Box::new(DefaultRule::new(path.into(), paths, self.keep)),
+        })
+    }
+
+    /// Delete generations older than maxage when path itself is not rotated.
+    fn delete_expired(&self, path: &Path, plan: &mut Plan, now: DateTime<Local>) -> Result<()> {
+        if self.maxage_opt.is_none() {
+            return Ok(());
+        }
+        let kept = self.keep_rule(path, now)?.delete_paths().clone();
+        for p in self.path_rule(path, now)?.delete_paths().iter().filter(|p| !kept.contains(p)) {
+            info!(path = p.to_str().unwrap(), "older than maxage, delete");
+            plan.delete(p)?;
+        }
+        Ok(())
+    }
+
+    /// Delete oldest generations until total storage size of path and its generations fits in maxtotal.
+    fn limit_total(&self, path: &Path, plan: &mut Plan, rule: &dyn PathRule, now: DateTime<Local>, maxtotal: usize) -> Result<()> {
+        let mut live = tree_size(path)?;
+        let mut generations = vec![];
+
+        if plan.is_dry_run() {
+            // nothing is moved in dry run, predict generations after rotation from current names
+            if let Some(p) = rule.init_path() {
+                generations.push((rule.next_path(&p).unwrap(), live));
+                live = 0;
+            }
+            for p in rule.existing_paths() {
+                if !rule.delete_paths().contains(&p) {
+                    let sz = tree_size(&p)?;
+                    generations.push((rule.next_path(&p).unwrap_or(p), sz));
+                }
+            }
+        } else {
+            // look at the directory again, generations are renamed and compressed
+            for p in self.path_rule(path, now)?.existing_paths() {
+                let sz = tree_size(&p)?;
+                generations.push((p, sz));
+            }
+        }
+
+        let mut total = live + generations.iter().map(|(_, sz)| sz).sum::<usize>();
+        for (p, sz) in generations.iter().rev() {
+            if total <= maxtotal {
+                break;
+            }
+            info!(path = p.to_str().unwrap(), total, maxtotal, "total size exceeded, delete");
+            plan.delete(p)?;
+            total -= sz;
+        }
+        Ok(())
+    }
+
+    pub fn get_paths(&self) -> &Vec<PathBuf> {
         &self.path
     }
+
+    fn copy_options(&self) -> CopyOptions {
+        CopyOptions { lock: self.copylock, method: self.copymethod }
+    }
+
+    fn cmd_timeout(&self) -> Option<std::time::Duration> {
+        self.cmd_timeout_opt.as_ref().map(|t| t.to_std())
+    }
+
+    pub fn get_depth(&self) -> Option<i32> {
+        self.depth_opt
+    }
+
+    pub fn has_size(&self) -> bool {
+        self.sz_opt.is_some() || self.minsize_opt.is_some() || self.maxsize_opt.is_some()
+    }
+}
+
+/// Environment of pre/post commands, paths are joined by newline if several paths are rotated.
+fn script_env(rotated: &[(PathBuf, PathBuf)]) -> BTreeMap<String, String> {
+    let join = |paths: Vec<String>| paths.join("\n");
+    BTreeMap::from([
+        ("FILEROTATE_SRC".to_string(), join(rotated.iter().map(|(src, _)| src.display().to_string()).collect())),
+        ("FILEROTATE_DST".to_string(), join(rotated.iter().map(|(_, dst)| dst.display().to_string()).collect())),
+    ])
 }
 
-fn size_check(sz_opt: Option<usize>, f_st: FileStat) -> bool {
-    match sz_opt {
-        Some(sz) => f_st.st_blocks * 512 > sz as i64,
+/// Files are rotated only if their size of kind is bigger than min.
+#[derive(Debug, Clone, Copy, Default)]
+struct SizeLimit {
+    min_opt: Option<usize>,
+    kind: SizeKind,
+}
+
+fn size_check(limit: SizeLimit, f_st: FileStat) -> bool {
+    match limit.min_opt {
+        Some(sz) => kind_size(&f_st, limit.kind) > sz,
         None => true
     }
 }
 
+fn mtime(path: &Path) -> Option<NaiveDateTime> {
+    let modified = metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(DateTime::<Local>::from(modified).naive_local())
+}
+
 fn regex_check(re_opt: Option<&Regex>, path: &Path) -> bool {
     match (re_opt, path.file_name().unwrap().to_str()) {
         (Some(re), Some(name)) => re.is_match(name),
@@ -145,9 +554,9 @@ fn regex_check(re_opt: Option<&Regex>, path: &Path) -> bool {
     }
 }
 
-fn move_create(src: PathBuf, dst: PathBuf, depth_opt: Option<i32>, sz_opt: Option<usize>, re_opt: Option<&Regex>) -> Result<()> {
+fn move_create(plan: &mut Plan, src: PathBuf, dst: PathBuf, create: &Create, depth_opt: Option<i32>, sz: SizeLimit, re_opt: Option<&Regex>) -> Result<()> {
     info!(src = src.to_str().unwrap() , dst = dst.to_str().unwrap() ,"move create");
-    if depth_opt.map_or(false, |n| n <= 0) {
+    if depth_opt.is_some_and(|n| n <= 0) {
         return Ok(());
     }
 
@@ -155,7 +564,7 @@ fn move_create(src: PathBuf, dst: PathBuf, depth_opt: Option<i32>, sz_opt: Optio
 
     if is_file(&f_st) {
         // check if size hit threshold
-        if !size_check(sz_opt, f_st) {
+        if !size_check(sz, f_st) {
             info!("size not matched, skipping");
             return Ok(());
         }
@@ -166,28 +575,29 @@ fn move_create(src: PathBuf, dst: PathBuf, depth_opt: Option<i32>, sz_opt: Optio
             return Ok(());
         }
 
-        rename(&src, &dst)?;
-        File::create(&src)?;
+        plan.rename(&src, &dst)?;
+        // moved file keeps its inode, new file takes mode and ownership from it
+        plan.create_like(&src, &dst, create)?;
         return Ok(());
     }
 
     if is_dir(&f_st) {
-        create_dir(&dst)?;
+        plan.create_dir(&dst)?;
         let entries = read_dir(&src)?;
         for res in entries {
             let entry = res?;
             let nxt_src = entry.path();
             let nxt_dst = dst.join(nxt_src.file_name().unwrap());
-            move_create(nxt_src, nxt_dst, depth_opt.map(|n| n - 1), sz_opt, re_opt)?;
+            move_create(plan, nxt_src, nxt_dst, create, depth_opt.map(|n| n - 1), sz, re_opt)?;
         }
     }
 
     Ok(())
 }
 
-fn copy_truncate(src: PathBuf, dst: PathBuf, depth_opt: Option<i32>, sz_opt: Option<usize>, re_opt: Option<&Regex>) -> Result<()> {
+fn copy_truncate(plan: &mut Plan, src: PathBuf, dst: PathBuf, opts: CopyOptions, depth_opt: Option<i32>, sz: SizeLimit, re_opt: Option<&Regex>) -> Result<()> {
     info!(src = src.to_str().unwrap() , dst = dst.to_str().unwrap() ,"copy truncate");
-    if depth_opt.map_or(false, |n| n <= 0) {
+    if depth_opt.is_some_and(|n| n <= 0) {
         return Ok(());
     }
 
@@ -195,7 +605,7 @@ fn copy_truncate(src: PathBuf, dst: PathBuf, depth_opt: Option<i32>, sz_opt: Opt
 
     if is_file(&f_st) {
         // check if size hit threshold
-        if !size_check(sz_opt, f_st) {
+        if !size_check(sz, f_st) {
             info!("size not matched, skipping");
             return Ok(());
         }
@@ -208,46 +618,49 @@ fn copy_truncate(src: PathBuf, dst: PathBuf, depth_opt: Option<i32>, sz_opt: Opt
 
         // do not copy zero size file, see: https://man7.org/linux/man-pages/man2/lseek.2.html
         if stat_size(&f_st) > 0 {
-            util::copy_truncate(&src, &dst)?;
+            plan.copy_truncate(&src, &dst, opts)?;
         } else {
-            File::create(&dst)?;
+            plan.create(&dst)?;
+            plan.copy_metadata(&src, &dst)?;
         }
         return Ok(());
     }
 
     if is_dir(&f_st) {
-        create_dir(&dst)?;
+        plan.create_dir(&dst)?;
         let entries = read_dir(&src)?;
         for res in entries {
             let entry = res?;
             let nxt_src = entry.path();
             let nxt_dst = dst.join(nxt_src.file_name().unwrap());
-            copy_truncate(nxt_src, nxt_dst, depth_opt.map(|n| n - 1), sz_opt, re_opt)?;
+            copy_truncate(plan, nxt_src, nxt_dst, opts, depth_opt.map(|n| n - 1), sz, re_opt)?;
         }
+        // after children are created which changes modification time of dst
+        plan.copy_metadata(&src, &dst)?;
     }
 
     Ok(())
 }
 
-fn delete(src: PathBuf, depth_opt: Option<i32>, sz_opt: Option<usize>, re_opt: Option<&Regex>) -> Result<()> {
-    recursive_iterate(src, depth_opt, sz_opt, re_opt, |path| {
+fn delete(plan: &mut Plan, src: PathBuf, depth_opt: Option<i32>, sz: SizeLimit, re_opt: Option<&Regex>) -> Result<()> {
+    recursive_iterate(plan, src, depth_opt, sz, re_opt, |plan, path| {
         info!(path = path.to_str().unwrap() ,"delete");
-        remove_file(path)?;
+        plan.delete(path)?;
         Ok(())
     })
 }
 
-fn truncate(src: PathBuf, depth_opt: Option<i32>, sz_opt: Option<usize>, re_opt: Option<&Regex>) -> Result<()> {
-    recursive_iterate(src, depth_opt, sz_opt, re_opt, |path| {
+fn truncate(plan: &mut Plan, src: PathBuf, depth_opt: Option<i32>, sz: SizeLimit, re_opt: Option<&Regex>) -> Result<()> {
+    recursive_iterate(plan, src, depth_opt, sz, re_opt, |plan, path| {
         info!(path = path.to_str().unwrap() ,"truncate");
-        util::truncate(path)?;
+        plan.truncate(path)?;
         Ok(())
     })
 }
 
-fn recursive_iterate<F>(src: PathBuf, depth_opt: Option<i32>, sz_opt: Option<usize>, re_opt: Option<&Regex>, f: F) -> Result<()>
-    where F: Fn(&Path) -> Result<()> + Copy {
-    if depth_opt.map_or(false, |n| n <= 0) {
+fn recursive_iterate<F>(plan: &mut Plan, src: PathBuf, depth_opt: Option<i32>, sz: SizeLimit, re_opt: Option<&Regex>, f: F) -> Result<()>
+    where F: Fn(&mut Plan, &Path) -> Result<()> + Copy {
+    if depth_opt.is_some_and(|n| n <= 0) {
         return Ok(());
     }
 
@@ -255,7 +668,7 @@ fn recursive_iterate<F>(src: PathBuf, depth_opt: Option<i32>, sz_opt: Option<usi
 
     if is_file(&f_st) {
         // check if size hit threshold
-        if !size_check(sz_opt, f_st) {
+        if !size_check(sz, f_st) {
             info!("size not matched, skipping");
             return Ok(());
         }
@@ -266,7 +679,7 @@ fn recursive_iterate<F>(src: PathBuf, depth_opt: Option<i32>, sz_opt: Option<usi
             return Ok(());
         }
 
-        f(&src)?;
+        f(plan, &src)?;
         return Ok(());
     }
 
@@ -275,7 +688,7 @@ fn recursive_iterate<F>(src: PathBuf, depth_opt: Option<i32>, sz_opt: Option<usi
         for res in entries {
             let entry = res?;
             let nxt_src = entry.path();
-            recursive_iterate(nxt_src, depth_opt.map(|n| n - 1), sz_opt, re_opt, f)?;
+            recursive_iterate(plan, nxt_src, depth_opt.map(|n| n - 1), sz, re_opt, f)?;
         }
     }
 
@@ -285,8 +698,13 @@ fn recursive_iterate<F>(src: PathBuf, depth_opt: Option<i32>, sz_opt: Option<usi
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::plan::Action;
+    use nix::sys::signal::Signal;
+    use std::os::unix::process::ExitStatusExt;
+    use nix::sys::stat::utimes;
+    use nix::sys::time::{TimeVal, TimeValLike};
     use std::fs::DirEntry;
-    use std::fs::{metadata};
+    use std::fs::{create_dir, File};
     use tempfile::tempdir;
 
     #[derive(Eq, PartialEq, Debug)]
@@ -298,11 +716,11 @@ mod tests {
     fn build_tree(dir: PathBuf, root: &Node) {
         match root {
             Node::File { name } => {
-                let path = dir.join(&name);
+                let path = dir.join(name);
                 create_with_leading_hole(&path, 4096, 4096).unwrap();
             }
             Node::Dir { name, children } => {
-                let path = dir.join(&name);
+                let path = dir.join(name);
                 create_dir(&path).unwrap();
                 for node in children.iter() {
                     build_tree(path.clone(), node)
@@ -341,6 +759,40 @@ mod tests {
         }
     }
 
+    fn new_rotate(path: PathBuf, keep: usize) -> Rotate {
+        Rotate {
+            path: vec![path],
+            keep,
+            depth_opt: None,
+            sz_opt: None,
+            minsize_opt: None,
+            maxsize_opt: None,
+            size_kind: SizeKind::Storage,
+            re_opt: None,
+            pre_opt: None,
+            post_opt: None,
+            sharedscripts: false,
+            signal_opt: None,
+            create: Create::default(),
+            copylock: false,
+            copymethod: CopyMethod::Auto,
+            cmd_timeout_opt: None,
+            date_opt: None,
+            compress_opt: None,
+            delaycompress: false,
+            freq_opt: None,
+            maxage_opt: None,
+            maxtotal_opt: None,
+            interval_opt: None,
+            cron_opt: None,
+            mode: Mode::MoveCreate,
+        }
+    }
+
+    fn run_action(argv: Vec<String>, env: BTreeMap<String, String>) -> Action {
+        Action::Command { argv, env, cwd: None, user: None }
+    }
+
     fn gen_tree(root: &str) -> Node {
         Node::Dir {
             name: root.to_string(),
@@ -363,7 +815,7 @@ mod tests {
 
     #[test]
     fn build_and_inspect_tree_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
         // let path = PathBuf::new();
         let tree = gen_tree("dir0");
 
@@ -373,7 +825,7 @@ mod tests {
 
     #[test]
     fn move_create_simple_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
         // let path = PathBuf::new();
 
         let tree0 = gen_tree("dir0");
@@ -382,14 +834,14 @@ mod tests {
         let path1 = path.join("dir0.1");
 
         build_tree(path, &tree0);
-        move_create(path0, path1.clone(), None, None, None).unwrap();
+        move_create(&mut Plan::new(false), path0, path1.clone(), &Create::default(), None, SizeLimit::default(), None).unwrap();
 
         assert!(inspect_tree(&tree1, path1));
     }
 
     #[test]
     fn move_create_dir_recursive_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
         // let path = PathBuf::new();
 
         let tree0 = gen_tree("dir0");
@@ -413,14 +865,14 @@ mod tests {
         let path1 = path.join("dir0.1");
 
         build_tree(path, &tree0);
-        move_create(path0, path1.clone(), Some(2), None, None).unwrap();
+        move_create(&mut Plan::new(false), path0, path1.clone(), &Create::default(), Some(2), SizeLimit::default(), None).unwrap();
 
         assert!(inspect_tree(&tree1, path1));
     }
 
     #[test]
     fn move_create_dir_regex_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
         // let path = PathBuf::new();
 
         let tree0 = gen_tree("dir0");
@@ -445,14 +897,14 @@ mod tests {
         build_tree(path, &tree0);
         let re = Regex::new(r".*\.log$").unwrap();
 
-        move_create(path0, path1.clone(), Some(3), None, Some(re).as_ref()).unwrap();
+        move_create(&mut Plan::new(false), path0, path1.clone(), &Create::default(), Some(3), SizeLimit::default(), Some(re).as_ref()).unwrap();
 
         assert!(inspect_tree(&tree1, path1));
     }
 
     #[test]
     fn copy_truncate_dir_simple_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
         // let path = PathBuf::new();
 
         let tree0 = gen_tree("dir0");
@@ -461,14 +913,14 @@ mod tests {
         let path1 = path.join("dir0.1");
 
         build_tree(path, &tree0);
-        copy_truncate(path0, path1.clone(), None, None, None).unwrap();
+        copy_truncate(&mut Plan::new(false), path0, path1.clone(), CopyOptions::default(), None, SizeLimit::default(), None).unwrap();
 
         assert!(inspect_tree(&tree1, path1));
     }
 
     #[test]
     fn rotate_file_simple_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
         // let path = env::current_dir().unwrap();
 
         let path0 = path.join("file");
@@ -479,32 +931,49 @@ mod tests {
         create_with_leading_hole(&path0, 4096, 4096).unwrap();
 
         let ro = Rotate {
-            path: path0.clone(),
+            path: vec![path0.clone()],
             keep: 3,
             depth_opt: None,
             sz_opt: None,
+            minsize_opt: None,
+            maxsize_opt: None,
+            size_kind: SizeKind::Storage,
             re_opt: None,
             pre_opt: None,
             post_opt: None,
+            sharedscripts: false,
+            signal_opt: None,
+            create: Create::default(),
+            copylock: false,
+            copymethod: CopyMethod::Auto,
+            cmd_timeout_opt: None,
+            date_opt: None,
+            compress_opt: None,
+            delaycompress: false,
+            freq_opt: None,
+            maxage_opt: None,
+            maxtotal_opt: None,
+            interval_opt: None,
+            cron_opt: None,
             mode: Mode::MoveCreate,
         };
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(path0.exists());
         assert!(path1.exists());
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(path0.exists());
         assert!(path1.exists());
         assert!(path2.exists());
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(path0.exists());
         assert!(path1.exists());
         assert!(path2.exists());
         assert!(!path3.exists());
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(path0.exists());
         assert!(path1.exists());
         assert!(path2.exists());
@@ -513,7 +982,7 @@ mod tests {
 
     #[test]
     fn rotate_file_size_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
         // let path = env::current_dir().unwrap();
 
         let path0 = path.join("file");
@@ -524,32 +993,49 @@ mod tests {
         create_with_leading_hole(&path0, 4096, 4096).unwrap();
 
         let ro = Rotate {
-            path: path0.clone(),
+            path: vec![path0.clone()],
             keep: 3,
             depth_opt: None,
             sz_opt: Some(ByteSize::new(2048)),
+            minsize_opt: None,
+            maxsize_opt: None,
+            size_kind: SizeKind::Storage,
             re_opt: None,
             pre_opt: None,
             post_opt: None,
+            sharedscripts: false,
+            signal_opt: None,
+            create: Create::default(),
+            copylock: false,
+            copymethod: CopyMethod::Auto,
+            cmd_timeout_opt: None,
+            date_opt: None,
+            compress_opt: None,
+            delaycompress: false,
+            freq_opt: None,
+            maxage_opt: None,
+            maxtotal_opt: None,
+            interval_opt: None,
+            cron_opt: None,
             mode: Mode::MoveCreate,
         };
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(path0.exists());
         assert!(path1.exists());
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(path0.exists());
         assert!(path1.exists());
         assert!(!path2.exists());
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(path0.exists());
         assert!(path1.exists());
         assert!(!path2.exists());
         assert!(!path3.exists());
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(path0.exists());
         assert!(path1.exists());
         assert!(!path2.exists());
@@ -558,7 +1044,7 @@ mod tests {
 
     #[test]
     fn rotate_dir_simple_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
         // let path = env::current_dir().unwrap();
 
         let tree0 = gen_tree("dir0");
@@ -572,32 +1058,49 @@ mod tests {
         build_tree(path, &tree0);
 
         let ro = Rotate {
-            path: path0.clone(),
+            path: vec![path0.clone()],
             keep: 3,
             depth_opt: None,
             sz_opt: None,
+            minsize_opt: None,
+            maxsize_opt: None,
+            size_kind: SizeKind::Storage,
             re_opt: None,
             pre_opt: None,
             post_opt: None,
+            sharedscripts: false,
+            signal_opt: None,
+            create: Create::default(),
+            copylock: false,
+            copymethod: CopyMethod::Auto,
+            cmd_timeout_opt: None,
+            date_opt: None,
+            compress_opt: None,
+            delaycompress: false,
+            freq_opt: None,
+            maxage_opt: None,
+            maxtotal_opt: None,
+            interval_opt: None,
+            cron_opt: None,
             mode: Mode::MoveCreate,
         };
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(inspect_tree(&tree0, path0.clone()));
         assert!(inspect_tree(&tree1, path1.clone()));
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(inspect_tree(&tree0, path0.clone()));
         assert!(inspect_tree(&tree1, path1.clone()));
         assert!(inspect_tree(&tree2, path2.clone()));
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(inspect_tree(&tree0, path0.clone()));
         assert!(inspect_tree(&tree1, path1.clone()));
         assert!(inspect_tree(&tree2, path2.clone()));
         assert!(!path3.exists());
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(inspect_tree(&tree0, path0));
         assert!(inspect_tree(&tree1, path1));
         assert!(inspect_tree(&tree2, path2));
@@ -606,47 +1109,89 @@ mod tests {
 
     #[test]
     fn rotate_missing_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
 
         let path0 = path.join("file0");
 
         let ro = Rotate {
-            path: path0,
+            path: vec![path0],
             keep: 2,
             depth_opt: None,
             sz_opt: None,
+            minsize_opt: None,
+            maxsize_opt: None,
+            size_kind: SizeKind::Storage,
             re_opt: None,
             pre_opt: None,
             post_opt: None,
+            sharedscripts: false,
+            signal_opt: None,
+            create: Create::default(),
+            copylock: false,
+            copymethod: CopyMethod::Auto,
+            cmd_timeout_opt: None,
+            date_opt: None,
+            compress_opt: None,
+            delaycompress: false,
+            freq_opt: None,
+            maxage_opt: None,
+            maxtotal_opt: None,
+            interval_opt: None,
+            cron_opt: None,
             mode: Mode::MoveCreate,
         };
 
-        assert!(ro.rotate().is_err());
+        assert!(ro.rotate(&mut Plan::new(false), &mut State::default()).is_err());
     }
 
     #[test]
-    fn rotate_keep_num_check_test() {
-        let path = tempdir().unwrap().into_path();
+    fn rotate_keep_num_large_test() {
+        let path = tempdir().unwrap().keep();
 
         let path0 = path.join("file0");
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
 
         let ro = Rotate {
-            path: path0,
-            keep: 11,
+            path: vec![path0.clone()],
+            keep: 12,
             depth_opt: None,
             sz_opt: None,
+            minsize_opt: None,
+            maxsize_opt: None,
+            size_kind: SizeKind::Storage,
             re_opt: None,
             pre_opt: None,
             post_opt: None,
+            sharedscripts: false,
+            signal_opt: None,
+            create: Create::default(),
+            copylock: false,
+            copymethod: CopyMethod::Auto,
+            cmd_timeout_opt: None,
+            date_opt: None,
+            compress_opt: None,
+            delaycompress: false,
+            freq_opt: None,
+            maxage_opt: None,
+            maxtotal_opt: None,
+            interval_opt: None,
+            cron_opt: None,
             mode: Mode::MoveCreate,
         };
 
-        assert!(ro.rotate().is_err());
+        for _ in 0..15 {
+            ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        }
+
+        for i in 1..12 {
+            assert!(path.join(format!("file0.{}", i)).exists());
+        }
+        assert!(!path.join("file0.12").exists());
     }
 
     #[test]
     fn rotate_delete_dir_test() {
-        let path = tempdir().unwrap().into_path();
+        let path = tempdir().unwrap().keep();
         // let path = env::current_dir().unwrap();
 
         let tree0 = gen_tree("dir0");
@@ -664,17 +1209,672 @@ mod tests {
         build_tree(path, &tree0);
 
         let ro = Rotate {
-            path: path0.clone(),
+            path: vec![path0.clone()],
             keep: 0,
             depth_opt: None,
             sz_opt: None,
+            minsize_opt: None,
+            maxsize_opt: None,
+            size_kind: SizeKind::Storage,
             re_opt: None,
             pre_opt: None,
             post_opt: None,
+            sharedscripts: false,
+            signal_opt: None,
+            create: Create::default(),
+            copylock: false,
+            copymethod: CopyMethod::Auto,
+            cmd_timeout_opt: None,
+            date_opt: None,
+            compress_opt: None,
+            delaycompress: false,
+            freq_opt: None,
+            maxage_opt: None,
+            maxtotal_opt: None,
+            interval_opt: None,
+            cron_opt: None,
             mode: Mode::MoveCreate,
         };
 
-        ro.rotate().unwrap();
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
         assert!(inspect_tree(&tree1, path0.clone()));
     }
+
+    #[test]
+    fn rotate_file_date_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file.log");
+        let old = path.join("file.log-2000-01-01");
+        let cur = path.join(format!("file.log-{}", Local::now().format("%Y-%m-%d")));
+
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+        File::create(&old).unwrap();
+
+        let ro = Rotate {
+            date_opt: Some("{name}-%Y-%m-%d".to_string()),
+            ..new_rotate(path0.clone(), 2)
+        };
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path0.exists());
+        assert!(cur.exists());
+        assert!(!old.exists());
+
+        // same day target already exists
+        let ro = Rotate {
+            keep: 3,
+            ..ro
+        };
+        assert!(ro.rotate(&mut Plan::new(false), &mut State::default()).is_err());
+        assert!(cur.exists());
+    }
+
+    #[test]
+    fn rotate_file_compress_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let path1_gz = path.join("file.1.gz");
+        let path2_gz = path.join("file.2.gz");
+        let path3_gz = path.join("file.3.gz");
+
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+
+        let ro = Rotate {
+            compress_opt: Some(Compression::Gzip),
+            ..new_rotate(path0.clone(), 3)
+        };
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(!path1.exists());
+        assert!(path1_gz.exists());
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path1_gz.exists());
+        assert!(path2_gz.exists());
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path1_gz.exists());
+        assert!(path2_gz.exists());
+        assert!(!path3_gz.exists());
+    }
+
+    #[test]
+    fn rotate_file_delaycompress_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let path2 = path.join("file.2");
+        let path2_zst = path.join("file.2.zst");
+        let path3_zst = path.join("file.3.zst");
+
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+
+        let ro = Rotate {
+            compress_opt: Some(Compression::Zstd),
+            delaycompress: true,
+            ..new_rotate(path0.clone(), 4)
+        };
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path1.exists());
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path1.exists());
+        assert!(!path2.exists());
+        assert!(path2_zst.exists());
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path1.exists());
+        assert!(path2_zst.exists());
+        assert!(path3_zst.exists());
+    }
+
+    #[test]
+    fn rotate_dry_run_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let path2 = path.join("file.2");
+
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+        create_with_leading_hole(&path1, 4096, 4096).unwrap();
+        create_with_leading_hole(&path2, 4096, 4096).unwrap();
+
+        let ro = Rotate {
+            pre_opt: Some(vec!["false".to_string()].into()),
+            compress_opt: Some(Compression::Gzip),
+            ..new_rotate(path0.clone(), 3)
+        };
+
+        let mut plan = Plan::new(true);
+        ro.rotate(&mut plan, &mut State::default()).unwrap();
+        assert_eq!(plan.actions(), &vec![
+            run_action(vec!["false".to_string()], script_env(&[(path0.clone(), path1.clone())])),
+            Action::Delete { path: path2.clone() },
+            Action::Rename { src: path1.clone(), dst: path2.clone() },
+            Action::Rename { src: path0.clone(), dst: path1.clone() },
+            Action::Create { path: path0.clone() },
+            Action::Compress { path: path1.clone(), format: Compression::Gzip },
+            Action::Compress { path: path2.clone(), format: Compression::Gzip },
+        ]);
+
+        assert_eq!(read_dir(&path).unwrap().count(), 3);
+        assert!(!path.join("file.1.gz").exists());
+    }
+
+    #[test]
+    fn frequency_test() {
+        let t = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        assert!(!Frequency::Hourly.is_due(t("2022-04-18 06:00"), t("2022-04-18 06:59")));
+        assert!(Frequency::Hourly.is_due(t("2022-04-18 06:59"), t("2022-04-18 07:00")));
+        assert!(!Frequency::Daily.is_due(t("2022-04-18 00:00"), t("2022-04-18 23:59")));
+        assert!(Frequency::Daily.is_due(t("2022-04-18 23:59"), t("2022-04-19 00:00")));
+        assert!(Frequency::Daily.is_due(t("2021-12-31 23:59"), t("2022-01-01 00:00")));
+        // 2022-04-18 is monday
+        assert!(!Frequency::Weekly.is_due(t("2022-04-18 00:00"), t("2022-04-24 23:59")));
+        assert!(Frequency::Weekly.is_due(t("2022-04-24 23:59"), t("2022-04-25 00:00")));
+        assert!(!Frequency::Monthly.is_due(t("2022-04-01 00:00"), t("2022-04-30 23:59")));
+        assert!(Frequency::Monthly.is_due(t("2022-04-30 23:59"), t("2022-05-01 00:00")));
+        assert!(!Frequency::Yearly.is_due(t("2022-01-01 00:00"), t("2022-12-31 23:59")));
+        assert!(Frequency::Yearly.is_due(t("2022-12-31 23:59"), t("2023-01-01 00:00")));
+        assert!(!Frequency::Daily.is_due(t("2022-04-19 00:00"), t("2022-04-18 00:00")));
+    }
+
+    #[test]
+    fn rotate_file_frequency_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let path2 = path.join("file.2");
+
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+
+        let ro = Rotate {
+            freq_opt: Some(Frequency::Daily),
+            ..new_rotate(path0.clone(), 3)
+        };
+
+        let mut state = State::default();
+        ro.rotate(&mut Plan::new(false), &mut state).unwrap();
+        assert!(path1.exists());
+        assert!(state.last(&path0).is_some());
+
+        // rotated today already
+        ro.rotate(&mut Plan::new(false), &mut state).unwrap();
+        assert!(!path2.exists());
+
+        state.update(&path0, Local::now() - chrono::Duration::days(1));
+        ro.rotate(&mut Plan::new(false), &mut state).unwrap();
+        assert!(path2.exists());
+    }
+
+    #[test]
+    fn rotate_file_size_kind_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let path2 = path.join("file.2");
+
+        // 4096 bytes of storage, 8192 bytes of apparent size
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+
+        let ro = Rotate {
+            minsize_opt: Some(ByteSize::new(6000)),
+            ..new_rotate(path0.clone(), 3)
+        };
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(!path1.exists());
+
+        let ro = Rotate { size_kind: SizeKind::Apparent, ..ro };
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path1.exists());
+
+        // not due, but over maxsize
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+        let ro = Rotate {
+            freq_opt: Some(Frequency::Daily),
+            maxsize_opt: Some(ByteSize::new(6000)),
+            ..new_rotate(path0.clone(), 3)
+        };
+        let mut state = State::default();
+        state.update(&path0, Local::now());
+        ro.rotate(&mut Plan::new(false), &mut state).unwrap();
+        assert!(!path2.exists());
+
+        let ro = Rotate { size_kind: SizeKind::Apparent, ..ro };
+        ro.rotate(&mut Plan::new(false), &mut state).unwrap();
+        assert!(path2.exists());
+
+        let ro: Rotate = serde_yaml::from_str("{path: /a, keep: 3, mode: MoveCreate, sizekind: Apparent, maxsize: 1G}").unwrap();
+        assert_eq!(ro.size_kind, SizeKind::Apparent);
+        assert_eq!(ro.maxsize_opt.unwrap().bytes, 1 << 30);
+        let ro: Rotate = serde_yaml::from_str("{path: /a, keep: 3, mode: MoveCreate, sizekind: apparent}").unwrap();
+        assert_eq!(ro.size_kind, SizeKind::Apparent);
+        let ro: Rotate = serde_yaml::from_str("{path: /a, keep: 3, mode: MoveCreate, sizekind: storage}").unwrap();
+        assert_eq!(ro.size_kind, SizeKind::Storage);
+
+        // maxsize is compared with each file of a directory, not their total
+        let dir0 = path.join("dir0");
+        create_dir(&dir0).unwrap();
+        std::fs::write(dir0.join("a"), vec![0; 4000]).unwrap();
+        std::fs::write(dir0.join("b"), vec![0; 4000]).unwrap();
+        let ro = Rotate {
+            freq_opt: Some(Frequency::Daily),
+            maxsize_opt: Some(ByteSize::new(6000)),
+            size_kind: SizeKind::Apparent,
+            ..new_rotate(dir0.clone(), 3)
+        };
+        state.update(&dir0, Local::now());
+        ro.rotate(&mut Plan::new(false), &mut state).unwrap();
+        assert!(!path.join("dir0.1").exists());
+
+        std::fs::write(dir0.join("b"), vec![0; 8000]).unwrap();
+        ro.rotate(&mut Plan::new(false), &mut state).unwrap();
+        assert!(path.join("dir0.1").exists());
+    }
+
+    #[test]
+    fn next_run_test() {
+        let dir = tempdir().unwrap();
+        let path0 = dir.path().join("file");
+        let poll = Duration::from_secs(60);
+        let now = Local::now();
+
+        let ro = new_rotate(path0.clone(), 3);
+        assert_eq!(ro.next_run(now, &poll).unwrap(), Some(now + chrono::Duration::seconds(60)));
+
+        let ro = Rotate {
+            interval_opt: Some(Duration::from_secs(3600)),
+            ..new_rotate(path0.clone(), 3)
+        };
+        assert_eq!(ro.next_run(now, &poll).unwrap(), Some(now + chrono::Duration::seconds(3600)));
+
+        let ro = Rotate {
+            interval_opt: Some("9999999999w".parse().unwrap()),
+            ..new_rotate(path0.clone(), 3)
+        };
+        assert!(ro.next_run(now, &poll).is_err());
+
+        let ro = Rotate {
+            cron_opt: Some(Cron::new("0 0 * * *").unwrap()),
+            interval_opt: Some(Duration::from_secs(3600)),
+            ..new_rotate(path0, 3)
+        };
+        let next = ro.next_run(now, &poll).unwrap().unwrap();
+        assert!(next > now);
+        assert_eq!(next.time(), chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn rotate_file_maxage_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let path2 = path.join("file.2");
+        let path3 = path.join("file.3");
+
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+        create_with_leading_hole(&path1, 4096, 4096).unwrap();
+        create_with_leading_hole(&path2, 4096, 4096).unwrap();
+        let old = TimeVal::seconds(Local::now().timestamp() - 40 * 24 * 3600);
+        utimes(&path2, &old, &old).unwrap();
+
+        let ro = Rotate {
+            maxage_opt: Some("30d".parse().unwrap()),
+            ..new_rotate(path0.clone(), 10)
+        };
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path0.exists());
+        assert!(path1.exists());
+        assert!(path2.exists());
+        assert!(!path3.exists());
+    }
+
+    #[test]
+    fn rotate_file_maxage_small_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let path2 = path.join("file.2");
+
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+        create_with_leading_hole(&path1, 4096, 4096).unwrap();
+        create_with_leading_hole(&path2, 4096, 4096).unwrap();
+        let old = TimeVal::seconds(Local::now().timestamp() - 40 * 24 * 3600);
+        utimes(&path2, &old, &old).unwrap();
+
+        // live file is below size, stale generation is still deleted
+        let ro = Rotate {
+            sz_opt: Some(ByteSize::new(1 << 20)),
+            maxage_opt: Some("30d".parse().unwrap()),
+            ..new_rotate(path0.clone(), 10)
+        };
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path0.exists());
+        assert!(path1.exists());
+        assert!(!path2.exists());
+        assert!(!path.join("file.3").exists());
+
+        // state keeps the last real rotation when only expired generations are deleted
+        create_with_leading_hole(&path2, 4096, 4096).unwrap();
+        utimes(&path2, &old, &old).unwrap();
+        let ro = Rotate {
+            minsize_opt: Some(ByteSize::new(1 << 20)),
+            freq_opt: Some(Frequency::Daily),
+            maxage_opt: Some("30d".parse().unwrap()),
+            ..new_rotate(path0.clone(), 10)
+        };
+        let last = Local::now() - chrono::Duration::days(2);
+        let mut state = State::default();
+        state.update(&path0, last);
+        ro.rotate(&mut Plan::new(false), &mut state).unwrap();
+        assert!(!path2.exists());
+        assert!(path1.exists());
+        assert_eq!(state.last(&path0), Some(last));
+    }
+
+    #[test]
+    fn rotate_file_maxtotal_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let path2 = path.join("file.2");
+        let path3 = path.join("file.3");
+        let path4 = path.join("file.4");
+
+        create_with_leading_hole(&path0, 0, 8192).unwrap();
+        create_with_leading_hole(&path1, 0, 4096).unwrap();
+        create_with_leading_hole(&path2, 0, 4096).unwrap();
+        create_with_leading_hole(&path3, 0, 8192).unwrap();
+
+        let ro = Rotate {
+            maxtotal_opt: Some(ByteSize::new(16384)),
+            ..new_rotate(path0.clone(), 10)
+        };
+
+        let mut plan = Plan::new(true);
+        ro.rotate(&mut plan, &mut State::default()).unwrap();
+        assert_eq!(plan.actions().last(), Some(&Action::Delete { path: path4.clone() }));
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path0.exists());
+        assert!(path1.exists());
+        assert!(path2.exists());
+        assert!(path3.exists());
+        assert!(!path4.exists());
+    }
+
+    #[test]
+    fn validate_test() {
+        let ro: Rotate = serde_yaml::from_str("{path: /var/log/a.log, keep: 11, mode: MoveCreate, precmd: echo}").unwrap();
+        assert!(ro.validate().is_empty());
+
+        let ro: Rotate = serde_yaml::from_str(r#"
+path: [var/log/a.log, /var/log/b.log]
+keep: 1001
+mode: MoveCreate
+postcmd: [nonexistent-command]
+signal: {name: HUP}
+"#).unwrap();
+        let fields: Vec<&str> = ro.validate().iter().map(|(field, _)| *field).collect();
+        assert_eq!(fields, vec!["path", "postcmd", "signal"]);
+
+        let ro: Rotate = serde_yaml::from_str("{path: /a, keep: 1, mode: MoveCreate, dateformat: \"{name}-%Y\"}").unwrap();
+        assert_eq!(ro.validate()[0].0, "dateformat");
+
+        // huge maxage is a config error instead of overflowing time arithmetic
+        assert!(serde_yaml::from_str::<Rotate>("{path: /a, keep: 3, mode: MoveCreate, maxage: 99999999999w}").is_err());
+        let dir = tempdir().unwrap();
+        let path0 = dir.path().join("file");
+        File::create(&path0).unwrap();
+        let ro = Rotate { maxage_opt: Some("9999999999w".parse().unwrap()), ..new_rotate(path0, 3) };
+        assert_eq!(ro.validate(), vec![("maxage", "maxage 9999999999w is out of range".to_string())]);
+        assert!(ro.rotate(&mut Plan::new(false), &mut State::default()).is_err());
+    }
+
+    #[test]
+    fn rotate_glob_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path_a = path.join("a.log");
+        let path_a1 = path.join("a.log.1");
+        let path_a2 = path.join("a.log.2");
+        let path_b = path.join("b.log");
+        let path_b1 = path.join("b.log.1");
+
+        create_with_leading_hole(&path_a, 4096, 4096).unwrap();
+        create_with_leading_hole(&path_a1, 4096, 4096).unwrap();
+        create_with_leading_hole(&path_b, 4096, 4096).unwrap();
+
+        let ro = new_rotate(path.join("*.log*"), 3);
+        assert_eq!(ro.paths().unwrap(), vec![path_a.clone(), path_b.clone()]);
+
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path_a.exists());
+        assert!(path_a1.exists());
+        assert!(path_a2.exists());
+        assert!(path_b.exists());
+        assert!(path_b1.exists());
+        assert!(!path.join("a.log.1.1").exists());
+
+        // overlapping patterns rotate a path once, numbered name without live path is a root
+        let path_c = path.join("c.2024");
+        create_with_leading_hole(&path_c, 4096, 4096).unwrap();
+        let ro = Rotate {
+            path: vec![path.join("*.log"), path_a.clone(), path.join("c.*")],
+            ..new_rotate(path_a.clone(), 3)
+        };
+        assert_eq!(ro.paths().unwrap(), vec![path_a.clone(), path_b.clone(), path_c.clone()]);
+    }
+
+    #[test]
+    fn path_list_test() {
+        let ro: Rotate = serde_yaml::from_str("path: /var/log/a.log\nkeep: 2\nmode: MoveCreate").unwrap();
+        assert_eq!(ro.get_paths(), &vec![PathBuf::from("/var/log/a.log")]);
+
+        let ro: Rotate = serde_yaml::from_str("path: [/var/log/a.log, /var/log/b/*.log]\nkeep: 2\nmode: MoveCreate").unwrap();
+        assert_eq!(ro.get_paths(), &vec![PathBuf::from("/var/log/a.log"), PathBuf::from("/var/log/b/*.log")]);
+
+        let ro = new_rotate(PathBuf::from("log/*.log"), 2);
+        assert!(ro.paths().is_err());
+    }
+
+    #[test]
+    fn rotate_sharedscripts_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path_a = path.join("a.log");
+        let path_b = path.join("b.log");
+        let path_a1 = path.join("a.log.1");
+        let path_b1 = path.join("b.log.1");
+
+        create_with_leading_hole(&path_a, 4096, 4096).unwrap();
+        create_with_leading_hole(&path_b, 4096, 4096).unwrap();
+
+        let pre = vec!["pre".to_string()];
+        let post = vec!["post".to_string()];
+        let ro = Rotate {
+            pre_opt: Some(pre.clone().into()),
+            post_opt: Some(post.clone().into()),
+            compress_opt: Some(Compression::Gzip),
+            ..new_rotate(path.join("*.log"), 2)
+        };
+
+        // per file scripts run around every path
+        let mut plan = Plan::new(true);
+        ro.rotate(&mut plan, &mut State::default()).unwrap();
+        let env_a = script_env(&[(path_a.clone(), path_a1.clone())]);
+        let env_b = script_env(&[(path_b.clone(), path_b1.clone())]);
+        assert_eq!(plan.actions(), &vec![
+            run_action(pre.clone(), env_a.clone()),
+            Action::Rename { src: path_a.clone(), dst: path_a1.clone() },
+            Action::Create { path: path_a.clone() },
+            run_action(post.clone(), env_a),
+            Action::Compress { path: path_a1.clone(), format: Compression::Gzip },
+            run_action(pre.clone(), env_b.clone()),
+            Action::Rename { src: path_b.clone(), dst: path_b1.clone() },
+            Action::Create { path: path_b.clone() },
+            run_action(post.clone(), env_b),
+            Action::Compress { path: path_b1.clone(), format: Compression::Gzip },
+        ]);
+
+        // shared scripts run once, compression waits for postcmd
+        let ro = Rotate { sharedscripts: true, ..ro };
+        let mut plan = Plan::new(true);
+        ro.rotate(&mut plan, &mut State::default()).unwrap();
+        assert_eq!(plan.actions(), &vec![
+            run_action(pre, BTreeMap::new()),
+            Action::Rename { src: path_a.clone(), dst: path_a1.clone() },
+            Action::Create { path: path_a.clone() },
+            Action::Rename { src: path_b.clone(), dst: path_b1.clone() },
+            Action::Create { path: path_b.clone() },
+            run_action(post, script_env(&[(path_a.clone(), path_a1.clone()), (path_b.clone(), path_b1.clone())])),
+            Action::Compress { path: path_a1.clone(), format: Compression::Gzip },
+            Action::Compress { path: path_b1.clone(), format: Compression::Gzip },
+        ]);
+    }
+
+    #[test]
+    fn rotate_cmd_status_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let out = path.join("out");
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+
+        // failed precmd leaves path untouched
+        let ro = Rotate {
+            pre_opt: Some(vec!["false".to_string()].into()),
+            ..new_rotate(path0.clone(), 2)
+        };
+        assert!(ro.rotate(&mut Plan::new(false), &mut State::default()).is_err());
+        assert!(!path1.exists());
+
+        // failed postcmd is reported after rotation
+        let script = format!("echo \"$FILEROTATE_DST\" > {}; exit 1", out.display());
+        let ro = Rotate {
+            post_opt: Some(vec!["sh".to_string(), "-c".to_string(), script].into()),
+            ..new_rotate(path0.clone(), 2)
+        };
+        let err = ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap_err();
+        assert!(err.to_string().contains("postcmd failed"));
+        assert!(path1.exists());
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), format!("{}\n", path1.display()));
+
+        // hung precmd is killed
+        let ro = Rotate {
+            pre_opt: Some(vec!["sleep".to_string(), "10".to_string()].into()),
+            cmd_timeout_opt: Some(Duration::from_secs(1)),
+            ..new_rotate(path0.clone(), 3)
+        };
+        let err = ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(!path.join("file.2").exists());
+    }
+
+    #[test]
+    fn rotate_signal_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+        let pidfile = path.join("pid");
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        std::fs::write(&pidfile, child.id().to_string()).unwrap();
+
+        let ro = Rotate {
+            signal_opt: Some(serde_yaml::from_str(&format!("{{name: TERM, pidfile: {}}}", pidfile.display())).unwrap()),
+            ..new_rotate(path0.clone(), 2)
+        };
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert!(path1.exists());
+        assert_eq!(child.wait().unwrap().signal(), Some(Signal::SIGTERM as i32));
+
+        // process is gone, pidfile is stale now
+        let err = ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap_err();
+        assert!(err.to_string().contains("stale pidfile"));
+    }
+
+    #[test]
+    fn rotate_file_keep_mode_test() {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let path0 = path.join("file");
+        let path1 = path.join("file.1");
+
+        create_with_leading_hole(&path0, 4096, 4096).unwrap();
+        set_permissions(&path0, Permissions::from_mode(0o600)).unwrap();
+
+        let ro = new_rotate(path0.clone(), 2);
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert_eq!(metadata(&path0).unwrap().permissions().mode() & 0o7777, 0o600);
+        assert_eq!(metadata(&path1).unwrap().permissions().mode() & 0o7777, 0o600);
+        assert_eq!(metadata(&path0).unwrap().len(), 0);
+
+        let ro = Rotate {
+            create: serde_yaml::from_str("{mode: \"0640\"}").unwrap(),
+            ..new_rotate(path0.clone(), 3)
+        };
+        ro.rotate(&mut Plan::new(false), &mut State::default()).unwrap();
+        assert_eq!(metadata(&path0).unwrap().permissions().mode() & 0o7777, 0o640);
+    }
+
+    #[test]
+    fn copy_truncate_keep_metadata_test() {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let path0 = path.join("dir0");
+        let path1 = path.join("dir0.1");
+
+        build_tree(path, &gen_tree("dir0"));
+        let file0 = path0.join("file0.txt");
+        set_permissions(&file0, Permissions::from_mode(0o600)).unwrap();
+        set_permissions(&path0, Permissions::from_mode(0o750)).unwrap();
+        let mtime = TimeVal::seconds(1000);
+        utimes(&file0, &mtime, &mtime).unwrap();
+        utimes(&path0, &mtime, &mtime).unwrap();
+
+        copy_truncate(&mut Plan::new(false), path0, path1.clone(), CopyOptions::default(), None, SizeLimit::default(), None).unwrap();
+
+        let meta = metadata(path1.join("file0.txt")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o600);
+        assert_eq!(meta.mtime(), 1000);
+        let meta = metadata(&path1).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o750);
+        assert_eq!(meta.mtime(), 1000);
+    }
 }
@@ -0,0 +1,64 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::Result;
+use std::path::Path;
+use std::time::Instant;
+use tracing::{debug, error, info, info_span};
+
+use crate::plan::Plan;
+use crate::report::JobReport;
+use crate::rotate::Rotate;
+use crate::state::State;
+
+/// Rotate jobs one by one, the state file is only opened when some job has a frequency.
+/// Dry run reads it without locking and never writes it.
+pub fn rotate_jobs(list: &[&Rotate], state_path: &Path, dry_run: bool) -> Result<Vec<(Plan, JobReport)>> {
+    let mut state = if !list.iter().any(|ro| ro.has_frequency()) {
+        State::default()
+    } else if dry_run {
+        State::read(state_path)?
+    } else {
+        State::open(state_path)?
+    };
+
+    let mut plans = vec![];
+    for ro in list.iter() {
+        // output of pre/post commands is logged within the job span
+        let _span = info_span!("job", path = paths_str(ro).as_str()).entered();
+        info!("start to rotate");
+        debug!(rotate = format!("{:?}", ro).as_str());
+        let start = Instant::now();
+        let mut plan = Plan::new(dry_run);
+        let res = ro.rotate(&mut plan, &mut state);
+        if let Err(e) = &res {
+            error!(error = format!("{}", e).as_str(), "failed to rotate");
+        } else {
+            info!("rotate success");
+        }
+        let err_opt = res.err().map(|e| e.to_string());
+        let report = JobReport::new(ro.get_paths(), &plan, err_opt.as_ref(), start.elapsed());
+        plans.push((plan, report));
+    }
+
+    if !dry_run {
+        state.save().unwrap_or_else(|e| error!(error = format!("{}", e).as_str(), "failed to save state"));
+    }
+    Ok(plans)
+}
+
+/// Paths of job joined for logs and plan output.
+pub fn paths_str(ro: &Rotate) -> String {
+    ro.get_paths().iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,152 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::{anyhow, Result};
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::Deserialize;
+use std::fs::{read, read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Signal sent to a process after rotation, e.g. `{name: HUP, pidfile: /run/nginx.pid}`.
+///
+/// Process is found by either its pidfile, or its name which signals every process
+/// with that name.
+#[derive(Deserialize, Debug)]
+pub struct SignalTarget {
+    #[serde(deserialize_with = "deserialize_signal")]
+    pub name: Signal,
+    #[serde(rename = "pidfile")]
+    pidfile_opt: Option<PathBuf>,
+    #[serde(rename = "process")]
+    process_opt: Option<String>,
+}
+
+impl SignalTarget {
+    /// Check exactly one way of finding the process is configured.
+    pub fn check(&self) -> Result<()> {
+        match (&self.pidfile_opt, &self.process_opt) {
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => Err(anyhow!("signal needs either pidfile or process")),
+        }
+    }
+
+    /// Find pids of the target processes, fails if none of them is alive.
+    pub fn pids(&self) -> Result<Vec<Pid>> {
+        match (&self.pidfile_opt, &self.process_opt) {
+            (Some(pidfile), None) => {
+                let content = read_to_string(pidfile)
+                    .map_err(|e| anyhow!("failed to read pidfile {}: {}", pidfile.display(), e))?;
+                let pid = content.trim().parse::<i32>()
+                    .ok()
+                    .filter(|&pid| pid > 0)
+                    .map(Pid::from_raw)
+                    .ok_or_else(|| anyhow!("invalid pidfile {}", pidfile.display()))?;
+                match kill(pid, None) {
+                    Err(Errno::ESRCH) => Err(anyhow!("stale pidfile {}, process {} not exists", pidfile.display(), pid)),
+                    _ => Ok(vec![pid]),
+                }
+            }
+            (None, Some(name)) => {
+                let pids = find_processes(name)?;
+                if pids.is_empty() {
+                    return Err(anyhow!("process {} not exists", name));
+                }
+                Ok(pids)
+            }
+            _ => Err(anyhow!("signal needs either pidfile or process")),
+        }
+    }
+}
+
+/// Accept signal names with or without `SIG` prefix, e.g. `HUP`, `SIGUSR1`.
+fn deserialize_signal<'de, D>(de: D) -> Result<Signal, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    use serde::de::Error;
+
+    let name = String::deserialize(de)?.to_ascii_uppercase();
+    let full = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+    Signal::from_str(&full).map_err(|_| D::Error::custom(format!("unknown signal {}", full)))
+}
+
+/// Pids of processes whose command name or executable name is name.
+fn find_processes(name: &str) -> Result<Vec<Pid>> {
+    let own = std::process::id() as i32;
+    let mut pids = vec![];
+    for res in read_dir("/proc")? {
+        let entry = res?;
+        let pid = match entry.file_name().to_str().and_then(|s| s.parse::<i32>().ok()) {
+            Some(pid) if pid != own => pid,
+            _ => continue,
+        };
+
+        // process may exit while scanning
+        let comm = read_to_string(entry.path().join("comm")).unwrap_or_default();
+        let cmdline = read(entry.path().join("cmdline")).unwrap_or_default();
+        let exe = cmdline.split(|&b| b == 0).next()
+            .and_then(|arg0| std::str::from_utf8(arg0).ok())
+            .and_then(|arg0| Path::new(arg0).file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or("");
+
+        if comm.trim_end() == name || exe == name {
+            pids.push(Pid::from_raw(pid));
+        }
+    }
+    pids.sort();
+    Ok(pids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use std::process::Command;
+    use tempfile::tempdir;
+
+    #[test]
+    fn signal_target_test() {
+        let target: SignalTarget = serde_yaml::from_str("{name: hup, pidfile: /run/a.pid}").unwrap();
+        assert_eq!(target.name, Signal::SIGHUP);
+        let target: SignalTarget = serde_yaml::from_str("{name: SIGUSR1, process: nginx}").unwrap();
+        assert_eq!(target.name, Signal::SIGUSR1);
+        assert!(serde_yaml::from_str::<SignalTarget>("{name: FOO, process: nginx}").is_err());
+
+        let dir = tempdir().unwrap();
+        let pidfile = dir.path().join("pid");
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+
+        write(&pidfile, format!("{}\n", pid)).unwrap();
+        let target = SignalTarget { name: Signal::SIGTERM, pidfile_opt: Some(pidfile.clone()), process_opt: None };
+        assert_eq!(target.pids().unwrap(), vec![pid]);
+        assert!(find_processes("sleep").unwrap().contains(&pid));
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        write(&pidfile, format!("{}\n", i32::MAX)).unwrap();
+        assert!(target.pids().unwrap_err().to_string().contains("stale"));
+        write(&pidfile, "abc").unwrap();
+        assert!(target.pids().is_err());
+        assert!(target.check().is_ok());
+        let target = SignalTarget { pidfile_opt: None, ..target };
+        assert!(target.check().is_err());
+        assert!(target.pids().is_err());
+        let target = SignalTarget { process_opt: Some("nonexistent-process".to_string()), ..target };
+        assert!(target.pids().unwrap_err().to_string().contains("not exists"));
+    }
+}
@@ -0,0 +1,148 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use nix::fcntl::{flock, FlockArg};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// Last rotation time of every path, persisted as a json file.
+///
+/// An exclusive lock on `<path>.lock` is held until the state is dropped, so concurrent
+/// invocations sharing one state file run one after another.
+#[derive(Debug, Default)]
+pub struct State {
+    path_opt: Option<PathBuf>,
+    _lock: Option<File>,
+    records: BTreeMap<PathBuf, DateTime<Local>>,
+}
+
+impl State {
+    pub fn open(path: &Path) -> Result<State> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let mut lock_name = path.as_os_str().to_os_string();
+        lock_name.push(".lock");
+        let lock = File::options().create(true).truncate(false).write(true).open(lock_name)?;
+        flock(lock.as_raw_fd(), FlockArg::LockExclusive)?;
+
+        let records = if path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(path)?))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(State {
+            path_opt: Some(path.into()),
+            _lock: Some(lock),
+            records,
+        })
+    }
+
+    /// Load state without taking the lock or creating anything, for dry run. A missing
+    /// state file reads as empty and `save` does nothing.
+    pub fn read(path: &Path) -> Result<State> {
+        let records = if path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(path)?))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(State {
+            records,
+            ..State::default()
+        })
+    }
+
+    pub fn last(&self, path: &Path) -> Option<DateTime<Local>> {
+        self.records.get(path).cloned()
+    }
+
+    pub fn update(&mut self, path: &Path, time: DateTime<Local>) {
+        self.records.insert(path.into(), time);
+    }
+
+    /// Write state to a temporary file and atomically rename it over the state file.
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path_opt {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut tmp = NamedTempFile::new_in(path.parent().unwrap_or_else(|| Path::new(".")))?;
+        {
+            let mut writer = BufWriter::new(tmp.as_file_mut());
+            serde_json::to_writer_pretty(&mut writer, &self.records)?;
+            writer.flush()?;
+        }
+        tmp.as_file().sync_all()?;
+        tmp.persist(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn state_save_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state").join("status");
+        let now = Local::now();
+
+        let mut state = State::open(&path).unwrap();
+        assert_eq!(state.last(Path::new("/var/log/a.log")), None);
+        state.update(Path::new("/var/log/a.log"), now);
+        state.save().unwrap();
+        drop(state);
+
+        let state = State::open(&path).unwrap();
+        assert_eq!(state.last(Path::new("/var/log/a.log")), Some(now));
+        assert_eq!(state.last(Path::new("/var/log/b.log")), None);
+    }
+
+    #[test]
+    fn state_read_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state").join("status");
+        let now = Local::now();
+
+        let mut state = State::read(&path).unwrap();
+        assert_eq!(state.last(Path::new("/var/log/a.log")), None);
+        state.update(Path::new("/var/log/a.log"), now);
+        state.save().unwrap();
+        assert!(!dir.path().join("state").exists());
+
+        let mut state = State::open(&path).unwrap();
+        state.update(Path::new("/var/log/a.log"), now);
+        state.save().unwrap();
+        drop(state);
+
+        let state = State::read(&path).unwrap();
+        assert_eq!(state.last(Path::new("/var/log/a.log")), Some(now));
+        let mut lock_name = path.as_os_str().to_os_string();
+        lock_name.push(".lock");
+        let lock = File::open(lock_name).unwrap();
+        flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock).unwrap();
+    }
+}
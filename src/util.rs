@@ -14,21 +14,31 @@
 
 #![allow(dead_code)]
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use nix::libc::off_t;
 use nix::libc::{S_IFDIR, S_IFMT, S_IFREG};
 use nix::sys::sendfile::sendfile;
-use nix::sys::stat::{FileStat};
+use nix::errno::Errno;
+use nix::fcntl::{copy_file_range, flock, FlockArg};
+use nix::ioctl_write_int;
+use nix::sys::stat::{fstat, stat, FileStat};
 use nix::unistd::{ftruncate, lseek, write, Whence};
 use rand::prelude::*;
 
 use sha2::{Digest, Sha256};
 use std::cmp::min;
-use std::fs::File;
+use std::fs::{read_dir, File};
 use std::io;
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::RawFd;
 use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::meta::copy_metadata;
 
 #[inline(always)]
 pub fn is_file(f_st: &FileStat) -> bool {
@@ -45,16 +55,200 @@ pub fn stat_size(f_st: &FileStat) -> usize {
     f_st.st_blocks as usize * 512
 }
 
-pub fn copy_truncate(src: &Path, dst: &Path) -> Result<()> {
+/// Which size of file is compared with size thresholds.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeKind {
+    /// allocated blocks, holes are not counted and compression of filesystem is applied
+    #[default]
+    #[serde(alias = "storage")]
+    Storage,
+    /// file length as listed by `ls -l`
+    #[serde(alias = "apparent")]
+    Apparent,
+}
+
+#[inline(always)]
+pub fn kind_size(f_st: &FileStat, kind: SizeKind) -> usize {
+    match kind {
+        SizeKind::Storage => stat_size(f_st),
+        SizeKind::Apparent => f_st.st_size as usize,
+    }
+}
+
+/// Storage size of file, or of all files in directory recursively.
+pub fn tree_size(path: &Path) -> Result<usize> {
+    let f_st = stat(path)?;
+    if !is_dir(&f_st) {
+        return Ok(stat_size(&f_st));
+    }
+
+    let mut sz = 0;
+    for res in read_dir(path)? {
+        sz += tree_size(&res?.path())?;
+    }
+    Ok(sz)
+}
+
+/// Size of file, or of the largest file in directory down to depth, the files a rotation
+/// compares with size thresholds one by one.
+pub fn max_file_size(path: &Path, kind: SizeKind, depth_opt: Option<i32>) -> Result<usize> {
+    if depth_opt.is_some_and(|n| n <= 0) {
+        return Ok(0);
+    }
+
+    let f_st = stat(path)?;
+    if !is_dir(&f_st) {
+        return Ok(kind_size(&f_st, kind));
+    }
+
+    let mut max = 0;
+    for res in read_dir(path)? {
+        max = max.max(max_file_size(&res?.path(), kind, depth_opt.map(|n| n - 1))?);
+    }
+    Ok(max)
+}
+
+/// How CopyTruncate copies data. `Auto` tries reflink, then `copy_file_range`, then `sendfile`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CopyMethod {
+    #[default]
+    Auto,
+    Reflink,
+    CopyFileRange,
+    Sendfile,
+}
+
+/// How CopyTruncate copies and truncates a file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyOptions {
+    pub lock: bool,
+    pub method: CopyMethod,
+}
+
+// FICLONE of linux/fs.h
+ioctl_write_int!(ficlone, 0x94, 9);
+
+/// Max rounds to copy data appended to source during copy before truncation.
+const COPY_ROUNDS: usize = 8;
+/// How long to try to take the lock of source before truncating without it.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Copy src to dst and truncate src, return number of bytes appended to src at the last
+/// moment which are lost.
+///
+/// The count is a lower bound: bytes appended between measuring the length of src and
+/// truncating it are lost without being counted, unless writers honor the lock.
+///
+/// Data appended during copy is copied again until the copy has caught up. If lock is set,
+/// the last round and truncation hold an exclusive flock on src, so writers taking the lock
+/// around their writes never lose data.
+pub fn copy_truncate(src: &Path, dst: &Path, opts: CopyOptions) -> Result<usize> {
+    let CopyOptions { lock, method } = opts;
     let src_f = File::options().read(true).write(true).open(src)?;
-    let dst_f = File::create(dst)?;
+    // copy of restricted file is not readable by others before its mode is copied
+    let dst_f = File::options().write(true).create(true).truncate(true).mode(0o600).open(dst)?;
+    let (src_fd, dst_fd) = (src_f.as_raw_fd(), dst_f.as_raw_fd());
+
+    let used = copy_data(src_fd, dst_fd, method)?;
+    info!(path = src.to_str().unwrap(), method = format!("{:?}", used).as_str(), "copied");
+
+    // appended data is never cloned, clone is for the whole file only
+    let method = if used == CopyMethod::Reflink { CopyMethod::Auto } else { used };
+    for _ in 0..COPY_ROUNDS {
+        if file_len(src_fd)? <= lseek(src_fd, 0, Whence::SeekCur)? as usize {
+            break;
+        }
+        copy_rest(src_fd, dst_fd, method)?;
+    }
+
+    let locked = lock && lock_file(src_fd)?;
+    if lock && !locked {
+        warn!(path = src.to_str().unwrap(), "failed to lock, truncate without lock");
+    }
 
-    sparse_copy(src_f.as_raw_fd(), dst_f.as_raw_fd())?;
+    copy_rest(src_fd, dst_fd, method)?;
     dst_f.sync_all()?;
+    // before truncation which changes modification time of src
+    copy_metadata(src, dst)?;
 
-    ftruncate(src_f.as_raw_fd(), 0)?;
+    let copied = lseek(src_fd, 0, Whence::SeekCur)? as usize;
+    let lost = file_len(src_fd)?.saturating_sub(copied);
+    ftruncate(src_fd, 0)?;
+    if locked {
+        flock(src_fd, FlockArg::Unlock)?;
+    }
 
-    Ok(())
+    Ok(lost)
+}
+
+/// Copy whole src to dst by method, return the method really used.
+fn copy_data(src_fd: RawFd, dst_fd: RawFd, method: CopyMethod) -> Result<CopyMethod> {
+    if let CopyMethod::Auto | CopyMethod::Reflink = method {
+        // safe as both are valid file descriptors
+        match unsafe { ficlone(dst_fd, src_fd as u64) } {
+            Ok(_) => {
+                // continue after the cloned part
+                let len = file_len(dst_fd)? as off_t;
+                lseek(src_fd, len, Whence::SeekSet)?;
+                lseek(dst_fd, len, Whence::SeekSet)?;
+                return Ok(CopyMethod::Reflink);
+            }
+            Err(e) if method == CopyMethod::Reflink => return Err(anyhow!("reflink not supported: {}", e)),
+            Err(_) => {}
+        }
+    }
+    sparse_copy(src_fd, dst_fd, method)
+}
+
+/// Copy from current offset of src to its end, return the method really used.
+fn copy_rest(src_fd: RawFd, dst_fd: RawFd, method: CopyMethod) -> Result<CopyMethod> {
+    copy_bytes(src_fd, dst_fd, usize::MAX, method)
+}
+
+/// Copy at most limit bytes from current offset of src, stop early at end of src.
+fn copy_bytes(src_fd: RawFd, dst_fd: RawFd, limit: usize, method: CopyMethod) -> Result<CopyMethod> {
+    const CHUNK: usize = 4096 * 256;
+    let mut left = limit;
+
+    if method != CopyMethod::Sendfile {
+        loop {
+            if left == 0 {
+                return Ok(CopyMethod::CopyFileRange);
+            }
+            match copy_file_range(src_fd, None, dst_fd, None, min(left, CHUNK)) {
+                Ok(0) => return Ok(CopyMethod::CopyFileRange),
+                Ok(n) => left -= n,
+                // not supported between these files, offsets are kept for sendfile to go on
+                Err(Errno::EXDEV | Errno::ENOSYS | Errno::EOPNOTSUPP | Errno::EINVAL) if method != CopyMethod::CopyFileRange => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    while left > 0 {
+        match sendfile(dst_fd, src_fd, None, min(left, CHUNK))? {
+            0 => break,
+            n => left -= n,
+        }
+    }
+    Ok(CopyMethod::Sendfile)
+}
+
+fn file_len(fd: RawFd) -> Result<usize> {
+    Ok(fstat(fd)?.st_size as usize)
+}
+
+/// Try to take an exclusive flock until timeout, a writer may hold it for a while.
+fn lock_file(fd: RawFd) -> Result<bool> {
+    let deadline = Instant::now() + LOCK_TIMEOUT;
+    loop {
+        match flock(fd, FlockArg::LockExclusiveNonblock) {
+            Ok(()) => return Ok(true),
+            Err(Errno::EWOULDBLOCK) if Instant::now() < deadline => sleep(Duration::from_millis(10)),
+            Err(Errno::EWOULDBLOCK) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+    }
 }
 
 pub fn truncate(src: &Path) -> Result<()> {
@@ -63,16 +257,39 @@ pub fn truncate(src: &Path) -> Result<()> {
     Ok(())
 }
 
-fn sparse_copy(src_fd: RawFd, dst_fd: RawFd) -> Result<usize> {
-    let offset = lseek(src_fd, 0, Whence::SeekData)?;
-    lseek(dst_fd, offset, Whence::SeekSet)?;
-    let mut n = 1;
-    let mut sz = 0;
-    while n > 0 {
-        n = sendfile(dst_fd, src_fd, None, 4096 * 256)?;
-        sz += n;
+/// Copy data extents of src to the same offsets of dst, so holes are kept as holes.
+///
+/// Offsets of both files are left at the end of src, a trailing hole is kept by setting
+/// length of dst.
+fn sparse_copy(src_fd: RawFd, dst_fd: RawFd, method: CopyMethod) -> Result<CopyMethod> {
+    let len = file_len(src_fd)? as off_t;
+    let mut used = method;
+    let mut offset = 0;
+
+    loop {
+        let data = match lseek(src_fd, offset, Whence::SeekData) {
+            Ok(data) => data,
+            // no more data after offset
+            Err(Errno::ENXIO) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let hole = lseek(src_fd, data, Whence::SeekHole)?;
+
+        lseek(src_fd, data, Whence::SeekSet)?;
+        lseek(dst_fd, data, Whence::SeekSet)?;
+        // once fallen back to sendfile, don't try copy_file_range for every extent
+        let m = if used == CopyMethod::Sendfile { used } else { method };
+        used = copy_bytes(src_fd, dst_fd, (hole - data) as usize, m)?;
+        offset = hole;
     }
-    Ok(sz)
+
+    if offset < len {
+        ftruncate(dst_fd, len)?;
+        offset = len;
+    }
+    lseek(src_fd, offset, Whence::SeekSet)?;
+    lseek(dst_fd, offset, Whence::SeekSet)?;
+    Ok(used)
 }
 
 pub fn create_with_leading_hole(path: &Path, hole_size: usize, data_size: usize) -> Result<File> {
@@ -102,7 +319,7 @@ fn file_digest(path: &Path) -> Result<Vec<u8>> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use nix::sys::stat::stat;
+    use std::os::unix::fs::FileExt;
     use tempfile::tempdir;
 
     const KIB: usize = 1024;
@@ -129,7 +346,7 @@ mod tests {
         create_with_leading_hole(&path_a, 16 * KIB, 8 * KIB).unwrap();
         let file_a = File::open(&path_a).unwrap();
         let file_b = File::create(&path_b).unwrap();
-        sparse_copy(file_a.as_raw_fd(), file_b.as_raw_fd()).unwrap();
+        sparse_copy(file_a.as_raw_fd(), file_b.as_raw_fd(), CopyMethod::Auto).unwrap();
         let size_a = storage_size(&path_a).unwrap();
         let size_b = storage_size(&path_b).unwrap();
         let digest_a = file_digest(&path_a).unwrap();
@@ -137,6 +354,32 @@ mod tests {
         assert_eq!(size_a, 8 * KIB);
         assert_eq!(size_b, 8 * KIB);
         assert_eq!(digest_a, digest_b);
+
+        // data extents between interior holes and a trailing hole
+        let file_a = File::create(&path_a).unwrap();
+        for (i, offset) in [0, 64 * KIB, 200 * KIB].iter().enumerate() {
+            file_a.write_at(&vec![i as u8 + 1; 8 * KIB], *offset as u64).unwrap();
+        }
+        file_a.set_len(512 * KIB as u64).unwrap();
+
+        for method in [CopyMethod::Auto, CopyMethod::Sendfile] {
+            let file_a = File::open(&path_a).unwrap();
+            let file_b = File::create(&path_b).unwrap();
+            sparse_copy(file_a.as_raw_fd(), file_b.as_raw_fd(), method).unwrap();
+            assert_eq!(lseek(file_a.as_raw_fd(), 0, Whence::SeekCur).unwrap(), 512 * KIB as off_t);
+            assert_eq!(lseek(file_b.as_raw_fd(), 0, Whence::SeekCur).unwrap(), 512 * KIB as off_t);
+            assert_eq!(storage_size(&path_b).unwrap(), 24 * KIB);
+            assert_eq!(file_b.metadata().unwrap().len(), 512 * KIB as u64);
+            assert_eq!(file_digest(&path_a).unwrap(), file_digest(&path_b).unwrap());
+        }
+
+        // file of only a hole
+        let file_a = File::create(&path_a).unwrap();
+        file_a.set_len(16 * KIB as u64).unwrap();
+        let file_b = File::create(&path_b).unwrap();
+        sparse_copy(file_a.as_raw_fd(), file_b.as_raw_fd(), CopyMethod::Auto).unwrap();
+        assert_eq!(storage_size(&path_b).unwrap(), 0);
+        assert_eq!(file_b.metadata().unwrap().len(), 16 * KIB as u64);
     }
 
     #[test]
@@ -146,7 +389,7 @@ mod tests {
         let path_b = dir.path().join("b");
         create_with_leading_hole(&path_a, 16 * KIB, 8 * KIB).unwrap();
         let digest_a = file_digest(&path_a).unwrap();
-        copy_truncate(&path_a, &path_b).unwrap();
+        assert_eq!(copy_truncate(&path_a, &path_b, CopyOptions::default()).unwrap(), 0);
         let size_a = storage_size(&path_a).unwrap();
         let size_b = storage_size(&path_b).unwrap();
         let digest_b = file_digest(&path_b).unwrap();
@@ -154,4 +397,65 @@ mod tests {
         assert_eq!(size_b, 8 * KIB);
         assert_eq!(digest_a, digest_b);
     }
+
+    #[test]
+    fn copy_truncate_concurrent_write_test() {
+        use std::io::Write;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let dir = tempdir().unwrap();
+        let path_a = dir.path().join("a");
+        let path_b = dir.path().join("b");
+        create_with_leading_hole(&path_a, 0, 1024 * KIB).unwrap();
+
+        // writer appends lines under flock like a cooperating logger
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (path_a, stop) = (path_a.clone(), stop.clone());
+            std::thread::spawn(move || {
+                let mut f = File::options().append(true).open(&path_a).unwrap();
+                let mut written = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    flock(f.as_raw_fd(), FlockArg::LockExclusive).unwrap();
+                    f.write_all(b"line\n").unwrap();
+                    flock(f.as_raw_fd(), FlockArg::Unlock).unwrap();
+                    written += 5;
+                }
+                written
+            })
+        };
+
+        sleep(Duration::from_millis(20));
+        let lost = copy_truncate(&path_a, &path_b, CopyOptions { lock: true, ..CopyOptions::default() }).unwrap();
+        sleep(Duration::from_millis(20));
+        stop.store(true, Ordering::Relaxed);
+        let written = writer.join().unwrap();
+
+        let len = |p: &Path| std::fs::metadata(p).unwrap().len() as usize;
+        assert_eq!(lost, 0);
+        assert_eq!(len(&path_b) + len(&path_a), 1024 * KIB + written);
+    }
+
+    #[test]
+    fn copy_method_test() {
+        let dir = tempdir().unwrap();
+        let path_a = dir.path().join("a");
+        let path_b = dir.path().join("b");
+
+        for method in [CopyMethod::Auto, CopyMethod::CopyFileRange, CopyMethod::Sendfile, CopyMethod::Reflink] {
+            create_with_leading_hole(&path_a, 16 * KIB, 8 * KIB).unwrap();
+            let digest_a = file_digest(&path_a).unwrap();
+            let res = copy_truncate(&path_a, &path_b, CopyOptions { method, ..CopyOptions::default() });
+
+            // reflink is only supported by filesystems like btrfs and xfs
+            if method == CopyMethod::Reflink && res.is_err() {
+                assert_eq!(storage_size(&path_a).unwrap(), 8 * KIB);
+                continue;
+            }
+            assert_eq!(res.unwrap(), 0);
+            assert_eq!(storage_size(&path_a).unwrap(), 0);
+            assert_eq!(file_digest(&path_b).unwrap(), digest_a);
+        }
+    }
 }
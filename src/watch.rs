@@ -0,0 +1,264 @@
+//! Copyright 2021 Liu BoFan
+//!
+//! Licensed under the Apache License, Version 2.0 (the "License");
+//! you may not use this file except in compliance with the License.
+//! You may obtain a copy of the License at
+//!
+//!     http://www.apache.org/licenses/LICENSE-2.0
+//!
+//! Unless required by applicable law or agreed to in writing, software
+//! distributed under the License is distributed on an "AS IS" BASIS,
+//! WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//! See the License for the specific language governing permissions and
+//! limitations under the License.
+
+use anyhow::Result;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use nix::unistd::close;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::collections::HashMap;
+use std::fs::read_dir;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::duration::Duration;
+use crate::rotate::Rotate;
+use crate::run;
+
+/// A watched path with indexes of jobs watching it and depth left for each of them.
+struct Watch {
+    path: PathBuf,
+    jobs: Vec<(usize, Option<i32>)>,
+}
+
+/// Maps inotify watches on job paths back to job indexes.
+struct Watcher {
+    inotify: Inotify,
+    wds: HashMap<WatchDescriptor, Watch>,
+}
+
+impl Watcher {
+    fn new() -> Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        Ok(Watcher { inotify, wds: HashMap::new() })
+    }
+
+    /// Drop all watches and watch paths of jobs with size threshold again.
+    fn watch_all(&mut self, jobs: &[Rotate]) {
+        for wd in self.wds.drain().map(|(wd, _)| wd) {
+            // watch is removed by kernel already if the file was deleted
+            let _ = self.inotify.rm_watch(wd);
+        }
+
+        for (i, ro) in jobs.iter().enumerate() {
+            if !ro.has_size() {
+                continue;
+            }
+            let paths = match ro.paths() {
+                Ok(paths) => paths,
+                Err(e) => {
+                    warn!(error = format!("{}", e).as_str(), "failed to expand paths");
+                    continue;
+                }
+            };
+            for path in paths.iter() {
+                if let Err(e) = self.watch(i, path, ro.get_depth()) {
+                    warn!(path = path.to_str().unwrap(), error = format!("{}", e).as_str(), "failed to watch");
+                }
+            }
+        }
+    }
+
+    /// Watch file itself, or directory tree whose files are in reach of depth.
+    fn watch(&mut self, i: usize, path: &Path, depth_opt: Option<i32>) -> Result<()> {
+        if path.is_file() {
+            return self.add(i, path, depth_opt, AddWatchFlags::IN_MODIFY);
+        }
+
+        // files in directory are rotated only if depth left for them
+        if !path.is_dir() || depth_opt.is_some_and(|n| n <= 1) {
+            return Ok(());
+        }
+
+        self.add(i, path, depth_opt, AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)?;
+        for res in read_dir(path)? {
+            let nxt = res?.path();
+            if nxt.is_dir() {
+                self.watch(i, &nxt, depth_opt.map(|n| n - 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, i: usize, path: &Path, depth_opt: Option<i32>, flags: AddWatchFlags) -> Result<()> {
+        let wd = self.inotify.add_watch(path, flags)?;
+        let watch = self.wds.entry(wd).or_insert_with(|| Watch { path: path.into(), jobs: vec![] });
+        if !watch.jobs.iter().any(|&(j, _)| j == i) {
+            watch.jobs.push((i, depth_opt));
+        }
+        Ok(())
+    }
+
+    /// Wait for events at most timeout milliseconds, return indexes of jobs which got events.
+    /// Directories created in or moved into a watched directory are watched as well.
+    fn wait(&mut self, timeout: i32) -> Result<Vec<usize>> {
+        let mut fds = [PollFd::new(self.inotify.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            // interrupted by signal
+            Ok(0) | Err(Errno::EINTR) => return Ok(vec![]),
+            Ok(_) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut jobs = vec![];
+        let mut dirs = vec![];
+        for event in self.inotify.read_events()? {
+            if let Some(watch) = self.wds.get(&event.wd) {
+                jobs.extend(watch.jobs.iter().map(|&(i, _)| i));
+                let created = event.mask.intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO);
+                if let (true, true, Some(name)) = (created, event.mask.contains(AddWatchFlags::IN_ISDIR), &event.name) {
+                    let path = watch.path.join(name);
+                    dirs.extend(watch.jobs.iter().map(|&(i, depth_opt)| (i, path.clone(), depth_opt.map(|n| n - 1))));
+                }
+            }
+        }
+
+        for (i, path, depth_opt) in dirs {
+            if let Err(e) = self.watch(i, &path, depth_opt) {
+                warn!(path = path.to_str().unwrap(), error = format!("{}", e).as_str(), "failed to watch");
+            }
+        }
+        Ok(jobs)
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let _ = close(self.inotify.as_raw_fd());
+    }
+}
+
+/// Rotate jobs with size threshold as soon as their paths are written and size grows beyond
+/// the threshold. Writes within debounce after the first one trigger a single evaluation.
+pub fn run<F>(load: F, state_path: &Path, debounce: &Duration) -> Result<()>
+    where F: Fn() -> Result<Vec<Rotate>> {
+    let reload = Arc::new(AtomicBool::new(false));
+    let term = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, reload.clone())?;
+    signal_hook::flag::register(SIGTERM, term.clone())?;
+    signal_hook::flag::register(SIGINT, term.clone())?;
+
+    let mut jobs = load()?;
+    let mut watcher = Watcher::new()?;
+    watcher.watch_all(&jobs);
+    let mut pending: HashMap<usize, Instant> = HashMap::new();
+    info!(jobs = jobs.len(), "watch started");
+
+    while !term.load(Ordering::Relaxed) {
+        if reload.swap(false, Ordering::Relaxed) {
+            match load() {
+                Ok(list) => {
+                    jobs = list;
+                    pending.clear();
+                    watcher.watch_all(&jobs);
+                    info!(jobs = jobs.len(), "config reloaded");
+                }
+                Err(e) => error!(error = format!("{}", e).as_str(), "failed to reload config, keep old jobs"),
+            }
+        }
+
+        // wake up at least every second to handle signals
+        for i in watcher.wait(1000)? {
+            pending.entry(i).or_insert_with(|| Instant::now() + debounce.to_std());
+        }
+
+        let now = Instant::now();
+        let mut due: Vec<usize> = pending.iter().filter(|(_, &t)| t <= now).map(|(&i, _)| i).collect();
+        if due.is_empty() {
+            continue;
+        }
+        due.sort();
+        for i in due.iter() {
+            pending.remove(i);
+        }
+
+        let list: Vec<&Rotate> = due.iter().map(|&i| &jobs[i]).collect();
+        if let Err(e) = run::rotate_jobs(&list, state_path, false) {
+            error!(error = format!("{}", e).as_str(), "failed to run jobs");
+        }
+
+        // rotated files are new inodes, watch them again
+        watcher.watch_all(&jobs);
+    }
+
+    info!("watch stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, File};
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn watcher_event_test() {
+        let dir = tempdir().unwrap();
+        let path_a = dir.path().join("a");
+        let path_b = dir.path().join("b");
+        let path_c = path_b.join("c");
+        File::create(&path_a).unwrap();
+        create_dir(&path_b).unwrap();
+        File::create(&path_c).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        watcher.watch(0, &path_a, None).unwrap();
+        watcher.watch(1, dir.path(), None).unwrap();
+        watcher.watch(2, dir.path(), Some(1)).unwrap();
+        assert!(watcher.wait(0).unwrap().is_empty());
+
+        File::options().append(true).open(&path_a).unwrap().write_all(b"a").unwrap();
+        let mut jobs = watcher.wait(1000).unwrap();
+        jobs.sort();
+        jobs.dedup();
+        assert_eq!(jobs, vec![0, 1]);
+
+        File::options().append(true).open(&path_c).unwrap().write_all(b"c").unwrap();
+        let mut jobs = watcher.wait(1000).unwrap();
+        jobs.dedup();
+        assert_eq!(jobs, vec![1]);
+    }
+
+    #[test]
+    fn watcher_new_dir_test() {
+        let dir = tempdir().unwrap();
+        let path_a = dir.path().join("a");
+        let path_b = path_a.join("b");
+        let path_c = path_b.join("c");
+
+        let mut watcher = Watcher::new().unwrap();
+        watcher.watch(0, dir.path(), None).unwrap();
+        watcher.watch(1, dir.path(), Some(3)).unwrap();
+
+        create_dir(&path_a).unwrap();
+        let mut jobs = watcher.wait(1000).unwrap();
+        jobs.sort();
+        assert_eq!(jobs, vec![0, 1]);
+
+        // b is beyond depth of job 1
+        create_dir(&path_b).unwrap();
+        let mut jobs = watcher.wait(1000).unwrap();
+        jobs.sort();
+        assert_eq!(jobs, vec![0, 1]);
+
+        File::create(&path_c).unwrap();
+        assert_eq!(watcher.wait(1000).unwrap(), vec![0]);
+    }
+}